@@ -28,7 +28,8 @@ fn main() {
             let address = format!("127.0.0.1:{}", 4302 + i);
             let mut node = Node::new(&address).unwrap();
             let address = node.address().unwrap();
-            tx.send((node.id().clone(), address)).unwrap();
+            tx.send((node.id().clone(), address, node.puzzle_solution().clone()))
+                .unwrap();
             while let Ok((source, message)) = node.recv_message() {
                 match message.kind {
                     rpc::MessageKind::Request(r) => {
@@ -43,6 +44,8 @@ fn main() {
                             }
                         }
                     }
+                    // Replies to the records we hand off to each other.
+                    rpc::MessageKind::Response(rpc::ResponseKind::Store(..)) => {}
                     other => panic!("Unexpected response {:?}", other),
                 }
             }
@@ -60,8 +63,8 @@ fn main() {
     println!("Main node: {:?}", node.id());
 
     // Let the other nodes know us.
-    for &(ref id, ref address) in &ids {
-        node.note_node(id, address);
+    for &(ref id, ref address, ref solution) in &ids {
+        node.note_node(id, address, solution);
         let msg =
             rpc::RPCMessage::new(node.id().clone(),
                                  rpc::MessageKind::Request(rpc::RequestKind::Ping));
@@ -84,8 +87,9 @@ fn main() {
 
     thread::spawn(move || {
         let mut node = Node::new("127.0.0.1:4301").unwrap();
-        for &(ref id, ref address) in &ids {
-            node.note_node(id, address);
+        node.set_disjoint_paths(3);
+        for &(ref id, ref address, ref solution) in &ids {
+            node.note_node(id, address, solution);
             let msg =
                 rpc::RPCMessage::new(node.id().clone(),
                                      rpc::MessageKind::Request(rpc::RequestKind::Ping));
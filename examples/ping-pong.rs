@@ -18,7 +18,7 @@ fn main() {
     let (tx, rx) = mpsc::channel();
     ::std::thread::spawn(move || {
         let mut node = Node::new("127.0.0.1:4300").unwrap();
-        tx.send(node.id().clone()).unwrap();
+        tx.send((node.id().clone(), node.puzzle_solution().clone())).unwrap();
         while let Ok((source, message)) = node.recv_message() {
             match message.kind {
                 rpc::MessageKind::Request(rpc::RequestKind::Ping) => {
@@ -33,12 +33,12 @@ fn main() {
         }
     });
 
-    let id = rx.recv().unwrap();
+    let (id, solution) = rx.recv().unwrap();
     let address = net::Ipv4Addr::new(127, 0, 0, 1);
     let address = net::SocketAddr::V4(net::SocketAddrV4::new(address, 4300));
 
     let mut node = Node::new("127.0.0.1:4301").unwrap();
-    node.note_node(&id, &address);
+    node.note_node(&id, &address, &solution);
     let msg =
         rpc::RPCMessage::new(node.id().clone(),
                              rpc::MessageKind::Request(rpc::RequestKind::Ping));
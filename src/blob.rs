@@ -0,0 +1,445 @@
+/*
+ * Kademlia.rs - A WIP Kademlia algorithm implementation in Rust.
+ *
+ * Copyright (C) 2017 Emilio Cobos Álvarez <emilio@crisal.io>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Storage of files and other big blobs, split in chunks.
+//!
+//! A blob is split into chunks, either of a fixed size or at boundaries
+//! derived from its content, so that inserting some bytes in a file only
+//! changes the chunks around them. Each chunk is stored as a content-addressed
+//! value, and so are the manifests listing their keys.
+//!
+//! A manifest lists at most `MAX_MANIFEST_ENTRIES` keys, so that it fits in a
+//! message. Bigger blobs get a tree of manifests, where the manifests at the
+//! bottom list chunks and the ones above them list other manifests. The key of
+//! the manifest at the root identifies the blob, and since every key is the
+//! hash of what it points to, it commits to the whole blob.
+//!
+//! Blobs are read back through a `BlobReader`, which walks the tree fetching
+//! chunks a few at a time in parallel. Content-addressed lookups already check
+//! each chunk against its key.
+
+use bincode;
+use node::Node;
+use node_id::NodeId;
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use storage::StoreError;
+
+/// The number of chunks a `BlobReader` fetches at once.
+pub const PARALLEL_FETCHES: usize = 8;
+
+/// How to split a blob into chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chunking {
+    /// Chunks of a fixed size, in bytes, except for the last one.
+    Fixed(usize),
+    /// Chunks whose boundaries depend on the content, using a rolling hash,
+    /// with the given minimum, average and maximum sizes, in bytes.
+    ContentDefined {
+        /// The minimum size of a chunk.
+        min: usize,
+        /// The average size of a chunk, rounded up to a power of two.
+        avg: usize,
+        /// The maximum size of a chunk.
+        max: usize,
+    },
+}
+
+impl Default for Chunking {
+    fn default() -> Self {
+        Chunking::ContentDefined {
+            min: 8 * 1024,
+            avg: 16 * 1024,
+            max: 32 * 1024,
+        }
+    }
+}
+
+/// An iterator over the chunks of a stream.
+pub struct Chunker<R> {
+    reader: io::Bytes<io::BufReader<R>>,
+    chunking: Chunking,
+    done: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    /// Creates a chunker that splits `reader` with the given strategy.
+    pub fn new(reader: R, chunking: Chunking) -> Self {
+        Chunker {
+            reader: io::BufReader::new(reader).bytes(),
+            chunking: chunking,
+            done: false,
+        }
+    }
+}
+
+impl<R: Read> Iterator for Chunker<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let (min, mask, max) = match self.chunking {
+            Chunking::Fixed(size) => (size, 0, size),
+            Chunking::ContentDefined { min, avg, max } => {
+                (min, avg.next_power_of_two() as u64 - 1, max)
+            }
+        };
+        if max == 0 {
+            self.done = true;
+            return Some(Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                           "Chunks can't be empty")));
+        }
+
+        let mut chunk = vec![];
+        let mut hash = 0u64;
+        while chunk.len() < max {
+            let byte = match self.reader.next() {
+                Some(Ok(byte)) => byte,
+                Some(Err(err)) => return Some(Err(err)),
+                None => {
+                    self.done = true;
+                    break;
+                }
+            };
+
+            chunk.push(byte);
+            hash = (hash << 1).wrapping_add(gear(byte));
+            if mask != 0 && chunk.len() >= min && hash & mask == 0 {
+                break;
+            }
+        }
+
+        if chunk.is_empty() {
+            return None;
+        }
+        Some(Ok(chunk))
+    }
+}
+
+/// The random-looking value of a byte for the gear rolling hash, from
+/// SplitMix64.
+fn gear(byte: u8) -> u64 {
+    let mut z = (byte as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// The most keys a manifest lists, which keeps it well under the size of a
+/// message.
+pub const MAX_MANIFEST_ENTRIES: usize = 1024;
+
+/// The most levels of manifests above the chunks, which is enough for blobs of
+/// `MAX_MANIFEST_ENTRIES ^ (MAX_MANIFEST_DEPTH + 1)` chunks.
+pub const MAX_MANIFEST_DEPTH: u32 = 4;
+
+/// A manifest of a chunked blob, or of a part of it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobManifest {
+    /// The size of the part of the blob this manifest describes, in bytes.
+    pub size: u64,
+    /// The number of levels of manifests below this one: zero if `entries`
+    /// are the keys of chunks, one if they're the keys of manifests listing
+    /// chunks, and so on.
+    pub depth: u32,
+    /// The keys of the chunks or manifests below this one, in order.
+    pub entries: Vec<NodeId>,
+}
+
+impl BlobManifest {
+    /// Serializes this manifest, to store it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self, bincode::Infinite)
+            .expect("Serializing to a Vec shouldn't fail")
+    }
+
+    /// Deserializes a manifest, returning `None` if `bytes` isn't a valid one,
+    /// or it lists too many entries or levels.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let manifest: BlobManifest = bincode::deserialize(bytes).ok()?;
+        if manifest.entries.len() > MAX_MANIFEST_ENTRIES ||
+           manifest.depth > MAX_MANIFEST_DEPTH {
+            return None;
+        }
+        Some(manifest)
+    }
+}
+
+/// The ways storing or reading a blob can fail.
+#[derive(Debug)]
+pub enum BlobError {
+    /// Reading the blob, or receiving messages, failed.
+    Io(io::Error),
+    /// Storing a chunk or the manifest failed.
+    Store(StoreError),
+    /// The manifest wasn't found, or is not valid.
+    NoManifest,
+    /// The blob needs more levels of manifests than `MAX_MANIFEST_DEPTH`.
+    TooBig,
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BlobError::Io(ref err) => write!(f, "I/O error: {}", err),
+            BlobError::Store(ref err) => write!(f, "Failed to store a chunk: {:?}", err),
+            BlobError::NoManifest => write!(f, "Manifest not found or invalid"),
+            BlobError::TooBig => write!(f, "Blob too big for its manifests"),
+        }
+    }
+}
+
+impl Error for BlobError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            BlobError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BlobError {
+    fn from(err: io::Error) -> Self {
+        BlobError::Io(err)
+    }
+}
+
+impl From<StoreError> for BlobError {
+    fn from(err: StoreError) -> Self {
+        BlobError::Store(err)
+    }
+}
+
+/// Splits the contents of `reader` into chunks, stores each of them in the
+/// network through `node`, and then stores the tree of manifests listing them.
+///
+/// Returns the key of the manifest at the root, which identifies the blob.
+pub fn store<R: Read>(node: &mut Node,
+                      reader: R,
+                      chunking: Chunking)
+                      -> Result<NodeId, BlobError> {
+    // The keys and sizes of the chunks or manifests of the current level.
+    let mut level = vec![];
+    for chunk in Chunker::new(reader, chunking) {
+        let chunk = chunk?;
+        let size = chunk.len() as u64;
+        level.push((node.try_store_immutable(chunk)?, size));
+    }
+
+    let mut depth = 0;
+    loop {
+        let mut parents = vec![];
+        for entries in level.chunks(MAX_MANIFEST_ENTRIES) {
+            let manifest = BlobManifest {
+                size: entries.iter().map(|&(_, size)| size).sum(),
+                depth: depth,
+                entries: entries.iter().map(|&(ref key, _)| key.clone()).collect(),
+            };
+            parents.push((node.try_store_immutable(manifest.to_bytes())?, manifest.size));
+        }
+        if parents.is_empty() {
+            let empty = BlobManifest { size: 0, depth: 0, entries: vec![] };
+            return Ok(node.try_store_immutable(empty.to_bytes())?);
+        }
+        if parents.len() == 1 {
+            return Ok(parents.pop().unwrap().0);
+        }
+        if depth == MAX_MANIFEST_DEPTH {
+            return Err(BlobError::TooBig);
+        }
+        level = parents;
+        depth += 1;
+    }
+}
+
+/// Finds the manifest at `key`, if any and valid.
+fn find_manifest(node: &mut Node, key: NodeId) -> io::Result<Option<BlobManifest>> {
+    Ok(node.find_immutable(key)?.and_then(|bytes| BlobManifest::from_bytes(&bytes)))
+}
+
+/// A reader of a blob stored in the network.
+pub struct BlobReader<'a> {
+    node: &'a mut Node,
+    manifest: BlobManifest,
+    /// The manifests from the root down to the one listing the next chunks to
+    /// fetch, each along with the index of its next entry to visit.
+    path: Vec<(BlobManifest, usize)>,
+    /// The chunks fetched but not read yet, in reverse order.
+    fetched: Vec<Vec<u8>>,
+    /// The part of the current chunk not read yet.
+    current: io::Cursor<Vec<u8>>,
+    /// The number of bytes read so far.
+    read: u64,
+}
+
+impl<'a> BlobReader<'a> {
+    /// Finds the manifest of the blob at `key`, and returns a reader for it.
+    pub fn open(node: &'a mut Node, key: NodeId) -> Result<Self, BlobError> {
+        let manifest = match find_manifest(node, key)? {
+            Some(manifest) => manifest,
+            None => return Err(BlobError::NoManifest),
+        };
+
+        Ok(BlobReader {
+            node: node,
+            manifest: manifest.clone(),
+            path: vec![(manifest, 0)],
+            fetched: vec![],
+            current: io::Cursor::new(vec![]),
+            read: 0,
+        })
+    }
+
+    /// Gets the manifest at the root of the blob.
+    pub fn manifest(&self) -> &BlobManifest {
+        &self.manifest
+    }
+
+    /// Fetches the next few chunks in parallel, descending into the manifests
+    /// listing them as needed.
+    ///
+    /// Returns `false` if there are no chunks left.
+    fn fetch(&mut self) -> io::Result<bool> {
+        loop {
+            let (depth, next, len) = match self.path.last() {
+                Some(&(ref manifest, next)) => (manifest.depth, next, manifest.entries.len()),
+                None => return Ok(false),
+            };
+            if next == len {
+                self.path.pop();
+                continue;
+            }
+
+            let end = if depth == 0 { cmp::min(next + PARALLEL_FETCHES, len) } else { next + 1 };
+            let keys = self.path.last().unwrap().0.entries[next..end].to_vec();
+            self.path.last_mut().unwrap().1 = end;
+
+            if depth > 0 {
+                match find_manifest(self.node, keys[0].clone())? {
+                    Some(manifest) if manifest.depth + 1 == depth => {
+                        self.path.push((manifest, 0));
+                        continue;
+                    }
+                    _ => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("Manifest {} not found or invalid",
+                                                          keys[0])));
+                    }
+                }
+            }
+
+            let chunks = self.node.find_immutables(&keys)?;
+            for (key, chunk) in keys.iter().zip(chunks.into_iter()).rev() {
+                match chunk {
+                    Some(chunk) => self.fetched.push(chunk),
+                    None => {
+                        return Err(io::Error::new(io::ErrorKind::NotFound,
+                                                  format!("Chunk {} not found", key)));
+                    }
+                }
+            }
+            return Ok(true);
+        }
+    }
+}
+
+impl<'a> Read for BlobReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read != 0 || buf.is_empty() {
+                self.read += read as u64;
+                return Ok(read);
+            }
+
+            if self.fetched.is_empty() && !self.fetch()? {
+                if self.read != self.manifest.size {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "Blob size mismatch"));
+                }
+                return Ok(0);
+            }
+
+            self.current = io::Cursor::new(self.fetched.pop().unwrap());
+        }
+    }
+}
+
+#[test]
+fn content_defined_chunks_resynchronize() {
+    use storage;
+
+    let data = (0..200_000u32).map(|i| gear(i as u8) as u8 ^ (i >> 8) as u8)
+        .collect::<Vec<_>>();
+    let chunking = Chunking::ContentDefined { min: 256, avg: 1024, max: 4096 };
+    let chunks = |data: &[u8]| {
+        Chunker::new(data, chunking)
+            .map(|c| storage::hash(&c.unwrap()))
+            .collect::<Vec<_>>()
+    };
+
+    let original = chunks(&data);
+    let mut edited = b"some prefix".to_vec();
+    edited.extend_from_slice(&data);
+    let edited = chunks(&edited);
+
+    // Only the first chunks should change.
+    let shared = original.iter().filter(|c| edited.contains(c)).count();
+    assert!(shared + 3 >= original.len());
+
+    let fixed = Chunker::new(&data[..], Chunking::Fixed(1000)).count();
+    assert_eq!(fixed, 200);
+    assert_ne!(original, edited);
+}
+
+#[test]
+fn big_blobs_get_a_tree_of_manifests() {
+    let mut node = Node::new("127.0.0.1:0").unwrap();
+    node.set_handoff_rate(0);
+
+    let data = (0..3 * MAX_MANIFEST_ENTRIES as u32 * 2)
+        .map(|i| gear(i as u8) as u8 ^ (i >> 8) as u8)
+        .collect::<Vec<_>>();
+    let key = store(&mut node, &data[..], Chunking::Fixed(2)).unwrap();
+
+    let mut reader = BlobReader::open(&mut node, key.clone()).unwrap();
+    assert_eq!(reader.manifest().depth, 1);
+    assert_eq!(reader.manifest().entries.len(), 3);
+    assert!(reader.manifest().to_bytes().len() < 64 * 1024);
+    let mut read = vec![];
+    reader.read_to_end(&mut read).unwrap();
+    assert_eq!(read, data);
+
+    let empty = store(&mut node, &b""[..], Chunking::default()).unwrap();
+    let mut read = vec![];
+    BlobReader::open(&mut node, empty).unwrap().read_to_end(&mut read).unwrap();
+    assert!(read.is_empty());
+
+    let mut chunker = Chunker::new(&b"data"[..], Chunking::Fixed(0));
+    assert_eq!(chunker.next().unwrap().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert!(chunker.next().is_none());
+
+    let flat = BlobManifest { size: 0, depth: 0, entries: vec![key; MAX_MANIFEST_ENTRIES + 1] };
+    assert!(BlobManifest::from_bytes(&flat.to_bytes()).is_none());
+}
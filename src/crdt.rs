@@ -0,0 +1,263 @@
+/*
+ * Kademlia.rs - A WIP Kademlia algorithm implementation in Rust.
+ *
+ * Copyright (C) 2017 Emilio Cobos Álvarez <emilio@crisal.io>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Values that can be merged instead of replaced, also known as CRDTs.
+//!
+//! Plain records holding one of these values are merged with the existing
+//! record by the storing nodes, so concurrent stores don't lose updates, and
+//! `Node::find_merged` merges the copies of different replicas.
+//!
+//! Custom mergeable values implement `Value::merge_from`, like the built-in
+//! ones do. Merging must be commutative, associative and idempotent, so that
+//! replicas converge regardless of the order in which they see updates.
+
+use node_id::NodeId;
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, btree_set};
+use std::fmt::Debug;
+use storage::Value;
+
+/// A grow-only set, to which elements can be added but never removed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GSet<T: Ord> {
+    elements: BTreeSet<T>,
+}
+
+impl<T: Ord + Clone> GSet<T> {
+    /// Creates an empty set.
+    pub fn new() -> Self {
+        GSet {
+            elements: BTreeSet::new(),
+        }
+    }
+
+    /// Adds an element to the set, returning whether it wasn't there yet.
+    pub fn insert(&mut self, element: T) -> bool {
+        self.elements.insert(element)
+    }
+
+    /// Returns whether the set contains `element`.
+    pub fn contains(&self, element: &T) -> bool {
+        self.elements.contains(element)
+    }
+
+    /// Iterates over the elements of the set, in order.
+    pub fn iter<'a>(&'a self) -> btree_set::Iter<'a, T> {
+        self.elements.iter()
+    }
+
+    /// Returns the number of elements in the set.
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    /// Returns whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+}
+
+impl<T> Value for GSet<T>
+    where T: Ord + Clone + Debug + Serialize + Deserialize + Send + 'static,
+{
+    fn merge_from(&mut self, other: &Self) -> bool {
+        self.elements.extend(other.elements.iter().cloned());
+        true
+    }
+}
+
+/// A last-writer-wins register, holding the value with the latest timestamp.
+///
+/// Ties are broken by the id of the writer, so that every replica picks the
+/// same value.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LwwRegister<T> {
+    value: T,
+    timestamp: u64,
+    writer: NodeId,
+}
+
+impl<T: Clone> LwwRegister<T> {
+    /// Creates a register holding `value`, written by `writer` at
+    /// `timestamp`.
+    pub fn new(value: T, timestamp: u64, writer: NodeId) -> Self {
+        LwwRegister {
+            value: value,
+            timestamp: timestamp,
+            writer: writer,
+        }
+    }
+
+    /// Gets the current value of the register.
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    /// Gets the timestamp of the current value.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Writes `value`, unless the register already holds a later one.
+    ///
+    /// Returns whether the value was written.
+    pub fn set(&mut self, value: T, timestamp: u64, writer: NodeId) -> bool {
+        if (timestamp, &writer) <= (self.timestamp, &self.writer) {
+            return false;
+        }
+        self.value = value;
+        self.timestamp = timestamp;
+        self.writer = writer;
+        true
+    }
+}
+
+impl<T> Value for LwwRegister<T>
+    where T: Clone + Debug + PartialEq + Serialize + Deserialize + Send + 'static,
+{
+    fn merge_from(&mut self, other: &Self) -> bool {
+        self.set(other.value.clone(), other.timestamp, other.writer.clone());
+        true
+    }
+}
+
+/// A counter that can be incremented and decremented concurrently by
+/// different replicas, each identified by a node id.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counter {
+    increments: BTreeMap<NodeId, u64>,
+    decrements: BTreeMap<NodeId, u64>,
+}
+
+impl Counter {
+    /// Creates a counter with a value of zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Increments the counter by `by` on behalf of `replica`, saturating at
+    /// `u64::MAX`.
+    pub fn increment(&mut self, replica: &NodeId, by: u64) {
+        let count = self.increments.entry(replica.clone()).or_insert(0);
+        *count = count.saturating_add(by);
+    }
+
+    /// Decrements the counter by `by` on behalf of `replica`, saturating at
+    /// `u64::MAX` decrements.
+    pub fn decrement(&mut self, replica: &NodeId, by: u64) {
+        let count = self.decrements.entry(replica.clone()).or_insert(0);
+        *count = count.saturating_add(by);
+    }
+
+    /// Gets the current value of the counter, clamped to the range of `i64`,
+    /// since the counts come from other replicas and can be arbitrarily big.
+    pub fn value(&self) -> i64 {
+        let sum = |counts: &BTreeMap<NodeId, u64>| {
+            counts.values().fold(0u64, |sum, count| sum.saturating_add(*count))
+        };
+        let (increments, decrements) = (sum(&self.increments), sum(&self.decrements));
+        if increments >= decrements {
+            cmp::min(increments - decrements, i64::max_value() as u64) as i64
+        } else {
+            -(cmp::min(decrements - increments, i64::max_value() as u64) as i64)
+        }
+    }
+}
+
+/// Merges the per-replica counts of `other` into `counts`, keeping the biggest
+/// one of each replica.
+fn merge_counts(counts: &mut BTreeMap<NodeId, u64>, other: &BTreeMap<NodeId, u64>) {
+    for (replica, count) in other {
+        let existing = counts.entry(replica.clone()).or_insert(0);
+        if *existing < *count {
+            *existing = *count;
+        }
+    }
+}
+
+impl Value for Counter {
+    fn merge_from(&mut self, other: &Self) -> bool {
+        merge_counts(&mut self.increments, &other.increments);
+        merge_counts(&mut self.decrements, &other.decrements);
+        true
+    }
+}
+
+#[test]
+fn concurrent_updates_converge() {
+    use record::Record;
+
+    let a = NodeId::from_bytes([1; 20]);
+    let b = NodeId::from_bytes([2; 20]);
+
+    let mut members = GSet::new();
+    members.insert("alice".to_owned());
+    let mut other = GSet::new();
+    other.insert("bob".to_owned());
+
+    let merged = Record::Plain(other).merge(Some(&Record::Plain(members)));
+    match merged {
+        Record::Plain(ref set) => {
+            assert!(set.contains(&"alice".to_owned()));
+            assert!(set.contains(&"bob".to_owned()));
+        }
+        _ => unreachable!(),
+    }
+
+    let mut first = LwwRegister::new(1, 10, a.clone());
+    let mut second = LwwRegister::new(2, 10, b.clone());
+    let old = first.clone();
+    first.merge_from(&second);
+    second.merge_from(&old);
+    assert_eq!(first, second);
+    assert_eq!(*first.get(), 2);
+
+    let mut one = Counter::new();
+    one.increment(&a, 3);
+    let mut two = one.clone();
+    one.increment(&a, 1);
+    two.decrement(&b, 2);
+    one.merge_from(&two);
+    two.merge_from(&one);
+    assert_eq!(one, two);
+    assert_eq!(one.value(), 2);
+}
+
+#[test]
+fn huge_counts_saturate() {
+    let a = NodeId::from_bytes([1; 20]);
+    let b = NodeId::from_bytes([2; 20]);
+
+    let mut one = Counter::new();
+    one.increment(&a, u64::max_value());
+    let mut two = Counter::new();
+    two.increment(&b, u64::max_value());
+    one.merge_from(&two);
+    one.increment(&a, 1);
+    assert_eq!(one.value(), i64::max_value());
+
+    one.decrement(&a, u64::max_value());
+    one.decrement(&b, u64::max_value());
+    assert_eq!(one.value(), 0);
+    let mut three = Counter::new();
+    three.decrement(&a, u64::max_value());
+    assert_eq!(three.value(), -i64::max_value());
+}
+
@@ -0,0 +1,156 @@
+/*
+ * Kademlia.rs - A WIP Kademlia algorithm implementation in Rust.
+ *
+ * Copyright (C) 2017 Emilio Cobos Álvarez <emilio@crisal.io>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Client-side encryption of private values.
+//!
+//! Values are encrypted before leaving the node with a key derived from a
+//! secret and the name of the value, and stored under a key also derived from
+//! them, so the nodes replicating a value learn neither its contents nor its
+//! name. Only the holders of the secret can find, decrypt and authenticate it.
+//!
+//! Values are stored as mutable records, signed with a keypair derived from
+//! the secret and the name too, so only the holders of the secret can replace
+//! them.
+//!
+//! Values are encrypted with XChaCha20-Poly1305, with a random nonce
+//! prepended to the ciphertext, and the key they're stored under as
+//! associated data, so that they can't be moved to other keys.
+
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
+use chacha20poly1305::aead::{Aead, Payload};
+use hmac::{Hmac, Mac};
+use identity::Keypair;
+use node_id::NodeId;
+use rand::Rng;
+use record::MutableRecord;
+use sha2::Sha256;
+
+/// The size of the nonces, in bytes.
+const NONCE_SIZE: usize = 24;
+
+/// A secret shared by the readers and writers of some private values.
+#[derive(Clone)]
+pub struct Secret {
+    bytes: [u8; 32],
+}
+
+impl Secret {
+    /// Creates a secret from its raw bytes.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Secret { bytes }
+    }
+
+    /// Generates a new random secret.
+    pub fn generate<R: Rng>(rng: &mut R) -> Self {
+        let mut bytes = [0; 32];
+        rng.fill_bytes(&mut bytes);
+        Secret { bytes }
+    }
+
+    /// Gets the raw bytes of this secret, to keep it somewhere.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.bytes
+    }
+
+    /// Derives 32 bytes for a given purpose and name.
+    fn derive(&self, purpose: &[u8], name: &[u8]) -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.bytes)
+            .expect("HMAC accepts keys of any size");
+        mac.update(purpose);
+        mac.update(&[0]);
+        mac.update(name);
+        let mut derived = [0; 32];
+        derived.copy_from_slice(&mac.finalize().into_bytes());
+        derived
+    }
+
+    /// Derives the keypair the value named `name` is signed with.
+    pub fn keypair_for(&self, name: &[u8]) -> Keypair {
+        Keypair::from_secret_bytes(self.derive(b"signing", name))
+    }
+
+    /// Derives the key the value named `name` is stored under, which is the
+    /// one of the mutable records of `keypair_for(name)`, and reveals nothing
+    /// about the name without the secret.
+    pub fn key_for(&self, name: &[u8]) -> NodeId {
+        MutableRecord::<Vec<u8>>::key_for(&self.keypair_for(name).public_key(), &[])
+    }
+
+    /// Gets the cipher for the value named `name`.
+    fn cipher(&self, name: &[u8]) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.derive(b"encryption", name).into())
+    }
+
+    /// Encrypts the value named `name`.
+    pub fn encrypt<R: Rng>(&self, rng: &mut R, name: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; NONCE_SIZE];
+        rng.fill_bytes(&mut nonce);
+
+        let key = self.key_for(name);
+        let payload = Payload {
+            msg: value,
+            aad: key.as_bytes(),
+        };
+        let ciphertext = self.cipher(name)
+            .encrypt(XNonce::from_slice(&nonce), payload)
+            .expect("Encrypting to a Vec shouldn't fail");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        sealed
+    }
+
+    /// Decrypts and authenticates the value named `name`, returning `None` if
+    /// it wasn't encrypted with this secret for this name, or was tampered
+    /// with.
+    pub fn decrypt(&self, name: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_SIZE {
+            return None;
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let key = self.key_for(name);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: key.as_bytes(),
+        };
+        self.cipher(name).decrypt(XNonce::from_slice(nonce), payload).ok()
+    }
+}
+
+#[test]
+fn encrypt_and_decrypt() {
+    use rand;
+
+    let mut rng = rand::OsRng::new().unwrap();
+    let secret = Secret::generate(&mut rng);
+    let other = Secret::generate(&mut rng);
+
+    assert_ne!(secret.key_for(b"db-password"), other.key_for(b"db-password"));
+    assert_ne!(secret.key_for(b"db-password"), secret.key_for(b"api-token"));
+
+    let sealed = secret.encrypt(&mut rng, b"db-password", b"hunter2");
+    assert_eq!(secret.decrypt(b"db-password", &sealed), Some(b"hunter2".to_vec()));
+    assert_eq!(secret.decrypt(b"api-token", &sealed), None);
+    assert_eq!(other.decrypt(b"db-password", &sealed), None);
+
+    let mut tampered = sealed.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert_eq!(secret.decrypt(b"db-password", &tampered), None);
+}
@@ -0,0 +1,244 @@
+/*
+ * Kademlia.rs - A WIP Kademlia algorithm implementation in Rust.
+ *
+ * Copyright (C) 2017 Emilio Cobos Álvarez <emilio@crisal.io>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Erasure coding of big blobs, so that they can be spread across many nodes
+//! without storing a whole copy in each of them.
+//!
+//! A blob is split into stripes, each of them made of data shards, and
+//! Reed-Solomon parity shards are computed from the data shards of each stripe.
+//! Any subset of as many shards of a stripe as data shards is enough to get the
+//! stripe back. Shards are at most `MAX_SHARD_SIZE` bytes, so that each of them
+//! fits in a single message. Each shard is stored as a content-addressed
+//! record, and a manifest listing the keys of the shards is stored as another
+//! one, whose key identifies the blob.
+
+use bincode;
+use node_id::NodeId;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::error::Error;
+use std::fmt;
+use storage::{self, StoreError};
+
+/// The maximum size of a shard, in bytes, which leaves room for the rest of a
+/// store request in a UDP datagram.
+pub const MAX_SHARD_SIZE: usize = 60 * 1024;
+
+/// The maximum number of shards in a stripe, data and parity shards together.
+pub const MAX_SHARDS: usize = 256;
+
+/// The reasons a blob can't be erasure-coded and stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErasureError {
+    /// There are no data shards, or more than `MAX_SHARDS` shards in a stripe.
+    InvalidShards,
+    /// Storing a shard or the manifest failed.
+    Store(StoreError),
+}
+
+impl From<StoreError> for ErasureError {
+    fn from(err: StoreError) -> Self {
+        ErasureError::Store(err)
+    }
+}
+
+impl fmt::Display for ErasureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErasureError::InvalidShards => {
+                write!(f, "Stripes need between 1 and {} shards, and a data shard",
+                       MAX_SHARDS)
+            }
+            ErasureError::Store(ref err) => write!(f, "Failed to store a shard: {:?}", err),
+        }
+    }
+}
+
+impl Error for ErasureError {}
+
+/// The description of an erasure-coded blob.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The size of the blob, in bytes.
+    pub size: u64,
+    /// The size of each shard, in bytes.
+    pub shard_size: usize,
+    /// The number of data shards of each stripe.
+    pub data_shards: usize,
+    /// The number of parity shards of each stripe.
+    pub parity_shards: usize,
+    /// The keys of the shards, that is, their hashes, stripe by stripe. The
+    /// data shards of each stripe go first.
+    pub shards: Vec<NodeId>,
+}
+
+impl Manifest {
+    /// Serializes this manifest, to store it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self, bincode::Infinite)
+            .expect("Serializing to a Vec shouldn't fail")
+    }
+
+    /// Deserializes a manifest, returning `None` if `bytes` isn't a valid one.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let manifest: Manifest = bincode::deserialize(bytes).ok()?;
+        if !manifest.is_valid() {
+            return None;
+        }
+        Some(manifest)
+    }
+
+    /// Gets the number of shards of each stripe.
+    pub fn stripe_len(&self) -> usize {
+        self.data_shards + self.parity_shards
+    }
+
+    /// Returns whether the shape of this manifest makes sense, and its size
+    /// fits in its data shards.
+    fn is_valid(&self) -> bool {
+        if self.data_shards == 0 || self.stripe_len() > MAX_SHARDS ||
+           self.shard_size == 0 || self.shard_size > MAX_SHARD_SIZE ||
+           self.shards.is_empty() || self.shards.len() % self.stripe_len() != 0 {
+            return false;
+        }
+        let stripes = self.shards.len() / self.stripe_len();
+        let capacity = (stripes as u64)
+            .saturating_mul(self.data_shards as u64)
+            .saturating_mul(self.shard_size as u64);
+        self.size <= capacity
+    }
+}
+
+/// Splits `blob` into stripes of `data_shards` data shards, and computes
+/// `parity_shards` parity shards for each of them, returning the manifest of
+/// the blob and all the shards.
+///
+/// Shards are as small as possible while keeping a single stripe, up to
+/// `MAX_SHARD_SIZE`, after which more stripes are added.
+pub fn encode(blob: &[u8],
+              data_shards: usize,
+              parity_shards: usize)
+              -> Result<(Manifest, Vec<Vec<u8>>), ErasureError> {
+    if data_shards == 0 || data_shards + parity_shards > MAX_SHARDS {
+        return Err(ErasureError::InvalidShards);
+    }
+    let codec = ReedSolomon::new(data_shards, parity_shards)
+        .map_err(|_| ErasureError::InvalidShards)?;
+
+    // Shards can't be empty, so even empty blobs get one byte per shard.
+    let shard_size = (blob.len() + data_shards - 1) / data_shards;
+    let shard_size = ::std::cmp::min(::std::cmp::max(1, shard_size), MAX_SHARD_SIZE);
+    let stripe_size = data_shards * shard_size;
+    let stripes = ::std::cmp::max(1, (blob.len() + stripe_size - 1) / stripe_size);
+
+    let mut all_shards = Vec::with_capacity(stripes * (data_shards + parity_shards));
+    for stripe in 0..stripes {
+        let start = ::std::cmp::min(stripe * stripe_size, blob.len());
+        let end = ::std::cmp::min(start + stripe_size, blob.len());
+        let mut shards = blob[start..end].chunks(shard_size)
+            .map(|chunk| chunk.to_vec())
+            .collect::<Vec<_>>();
+        shards.resize(data_shards + parity_shards, vec![]);
+        for shard in &mut shards {
+            shard.resize(shard_size, 0);
+        }
+
+        codec.encode(&mut shards).map_err(|_| ErasureError::InvalidShards)?;
+        all_shards.extend(shards);
+    }
+
+    let manifest = Manifest {
+        size: blob.len() as u64,
+        shard_size: shard_size,
+        data_shards: data_shards,
+        parity_shards: parity_shards,
+        shards: all_shards.iter().map(|s| storage::hash(s)).collect(),
+    };
+    Ok((manifest, all_shards))
+}
+
+/// Gets a blob back from its shards, in the order of the manifest, some of
+/// which may be missing.
+///
+/// Returns `None` if there aren't enough shards in some stripe, or they don't
+/// match the manifest, or the manifest isn't valid.
+pub fn decode(manifest: &Manifest, mut shards: Vec<Option<Vec<u8>>>) -> Option<Vec<u8>> {
+    if !manifest.is_valid() || shards.len() != manifest.shards.len() {
+        return None;
+    }
+
+    for (shard, key) in shards.iter_mut().zip(&manifest.shards) {
+        if shard.as_ref().map_or(false, |s| storage::hash(s) != *key) {
+            *shard = None;
+        }
+    }
+
+    let codec = ReedSolomon::new(manifest.data_shards, manifest.parity_shards).ok()?;
+    let mut blob = Vec::with_capacity(manifest.size as usize);
+    for stripe in shards.chunks_mut(manifest.stripe_len()) {
+        codec.reconstruct_data(stripe).ok()?;
+        for shard in stripe.iter_mut().take(manifest.data_shards) {
+            blob.extend(shard.take()?);
+        }
+    }
+    if (blob.len() as u64) < manifest.size {
+        return None;
+    }
+    blob.truncate(manifest.size as usize);
+    Some(blob)
+}
+
+#[test]
+fn reconstructs_from_any_subset() {
+    let blob = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+    let (manifest, shards) = encode(&blob, 4, 2).unwrap();
+    assert_eq!(shards.len(), 6);
+
+    let manifest = Manifest::from_bytes(&manifest.to_bytes()).unwrap();
+    let mut partial = shards.into_iter().map(Some).collect::<Vec<_>>();
+    partial[0] = None;
+    partial[3] = None;
+    assert_eq!(decode(&manifest, partial.clone()), Some(blob));
+
+    partial[5] = None;
+    assert_eq!(decode(&manifest, partial), None);
+}
+
+#[test]
+fn shards_fit_in_a_message() {
+    assert_eq!(encode(b"blob", 0, 2), Err(ErasureError::InvalidShards));
+    assert_eq!(encode(b"blob", 200, 57), Err(ErasureError::InvalidShards));
+
+    let blob = (0..3 * MAX_SHARD_SIZE + 1).map(|i| i as u8).collect::<Vec<_>>();
+    let (manifest, shards) = encode(&blob, 2, 1).unwrap();
+    assert_eq!(manifest.shard_size, MAX_SHARD_SIZE);
+    assert_eq!(shards.len(), 2 * 3);
+    assert!(shards.iter().all(|s| s.len() == MAX_SHARD_SIZE));
+
+    // Losing a shard of each stripe is fine.
+    let mut partial = shards.into_iter().map(Some).collect::<Vec<_>>();
+    partial[0] = None;
+    partial[4] = None;
+    assert_eq!(decode(&manifest, partial.clone()), Some(blob));
+
+    // Manifests claiming more than their shards hold are refused.
+    let mut lying = manifest.clone();
+    lying.size = u64::max_value();
+    assert_eq!(Manifest::from_bytes(&lying.to_bytes()), None);
+    assert_eq!(decode(&lying, partial), None);
+}
@@ -0,0 +1,121 @@
+/*
+ * Kademlia.rs - A WIP Kademlia algorithm implementation in Rust.
+ *
+ * Copyright (C) 2017 Emilio Cobos Álvarez <emilio@crisal.io>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Cryptographic node identities, as described in [S/Kademlia][skademlia].
+//!
+//! Each node owns an Ed25519 keypair, and its `NodeId` is derived from the hash
+//! of its public key. Every message is signed, so a receiver can check that
+//! the sender actually owns the id it claims, which means that nodes can't
+//! pick arbitrary ids next to the keys they want to attack.
+//!
+//! [skademlia]: https://doi.org/10.1109/ICPADS.2007.4447808
+
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use ed25519_dalek::Signature as DalekSignature;
+use node_id::NodeId;
+use rand::Rng;
+
+/// The raw bytes of an Ed25519 public key.
+pub type PublicKey = [u8; 32];
+
+/// The raw bytes of an Ed25519 signature.
+///
+/// This is a `Vec` instead of a `[u8; 64]` only because serde doesn't know how
+/// to serialize arrays that big.
+pub type Signature = Vec<u8>;
+
+/// Derives the `NodeId` that corresponds to a given public key.
+pub fn node_id_for(public_key: &PublicKey) -> NodeId {
+    NodeId::digest(public_key)
+}
+
+/// Checks that `signature` is a valid signature of `data` made with the
+/// secret key corresponding to `public_key`.
+pub fn verify(public_key: &PublicKey, data: &[u8], signature: &[u8]) -> bool {
+    let key = match VerifyingKey::from_bytes(public_key) {
+        Ok(k) => k,
+        Err(..) => return false,
+    };
+
+    let signature = match DalekSignature::from_slice(signature) {
+        Ok(s) => s,
+        Err(..) => return false,
+    };
+
+    key.verify(data, &signature).is_ok()
+}
+
+/// An Ed25519 keypair identifying a node in the network.
+pub struct Keypair {
+    signing_key: SigningKey,
+}
+
+impl Keypair {
+    /// Generates a new random keypair.
+    pub fn generate<R>(rng: &mut R) -> Self
+        where R: Rng,
+    {
+        let mut secret = [0; 32];
+        rng.fill_bytes(&mut secret);
+        Self::from_secret_bytes(secret)
+    }
+
+    /// Creates a keypair from the raw bytes of a secret key, for example one
+    /// that was persisted to disk.
+    pub fn from_secret_bytes(secret: [u8; 32]) -> Self {
+        Keypair {
+            signing_key: SigningKey::from_bytes(&secret),
+        }
+    }
+
+    /// Gets the raw bytes of the secret key.
+    pub fn secret_bytes(&self) -> [u8; 32] {
+        self.signing_key.to_bytes()
+    }
+
+    /// Gets the public key of this keypair.
+    pub fn public_key(&self) -> PublicKey {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    /// Gets the `NodeId` derived from this keypair.
+    pub fn node_id(&self) -> NodeId {
+        node_id_for(&self.public_key())
+    }
+
+    /// Signs an arbitrary piece of data.
+    pub fn sign(&self, data: &[u8]) -> Signature {
+        self.signing_key.sign(data).to_bytes().to_vec()
+    }
+}
+
+#[test]
+fn sign_and_verify() {
+    use rand;
+
+    let mut rng = rand::OsRng::new().unwrap();
+    let keypair = Keypair::generate(&mut rng);
+    let other = Keypair::generate(&mut rng);
+
+    let signature = keypair.sign(b"hello");
+    assert!(verify(&keypair.public_key(), b"hello", &signature));
+    assert!(!verify(&keypair.public_key(), b"hellp", &signature));
+    assert!(!verify(&other.public_key(), b"hello", &signature));
+    assert_ne!(keypair.node_id(), other.node_id());
+}
@@ -20,7 +20,9 @@
 //! A K-bucket.
 
 use node_id::NodeId;
+use puzzle;
 use std::collections::VecDeque;
+use std::collections::vec_deque;
 use std::net::SocketAddr;
 use std::collections::HashSet;
 
@@ -32,12 +34,17 @@ pub struct KBucketEntry {
     node_id: NodeId,
     /// The socket address (ip, port) pair.
     ip: SocketAddr,
+    /// The solution of this node to the dynamic crypto puzzle.
+    puzzle_solution: puzzle::Solution,
 }
 
 impl KBucketEntry {
     /// Trivially constructs a new KBucketEntry for a given node.
-    pub fn new(node_id: NodeId, ip: SocketAddr) -> Self {
-        KBucketEntry { node_id, ip }
+    pub fn new(node_id: NodeId,
+               ip: SocketAddr,
+               puzzle_solution: puzzle::Solution)
+               -> Self {
+        KBucketEntry { node_id, ip, puzzle_solution }
     }
 
     /// Get the id associated with this entry.
@@ -49,6 +56,11 @@ impl KBucketEntry {
     pub fn address(&self) -> &SocketAddr {
         &self.ip
     }
+
+    /// Get the solution to the dynamic puzzle associated with this entry.
+    pub fn puzzle_solution(&self) -> &puzzle::Solution {
+        &self.puzzle_solution
+    }
 }
 
 /// The `k` constant as described in the paper:
@@ -97,14 +109,19 @@ impl KBucket {
     /// the list.
     pub fn saw_node(&mut self,
                     id: &NodeId,
-                    address: &SocketAddr)
+                    address: &SocketAddr,
+                    puzzle_solution: &puzzle::Solution)
                     -> Option<KBucketEntry> {
         let existing_index =
             self.entries.iter().position(|e| e.node_id == *id);
 
         let new_entry = match existing_index {
             Some(i) => self.entries.remove(i).unwrap(),
-            None => KBucketEntry::new(id.clone(), address.clone()),
+            None => {
+                KBucketEntry::new(id.clone(),
+                                  address.clone(),
+                                  puzzle_solution.clone())
+            }
         };
 
         self.entries.push_back(new_entry);
@@ -115,4 +132,16 @@ impl KBucket {
             None
         }
     }
+
+    /// Removes the entry for a given node, if present.
+    pub fn remove(&mut self, id: &NodeId) -> Option<KBucketEntry> {
+        let index = self.entries.iter().position(|e| e.node_id == *id)?;
+        self.entries.remove(index)
+    }
+
+    /// Iterates over the entries of this bucket, from the least recently seen
+    /// to the most recently seen.
+    pub fn iter<'a>(&'a self) -> vec_deque::Iter<'a, KBucketEntry> {
+        self.entries.iter()
+    }
 }
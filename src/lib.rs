@@ -24,15 +24,34 @@
 #![allow(dead_code)]
 
 extern crate bincode;
+extern crate chacha20poly1305;
+extern crate crc32fast;
+extern crate ed25519_dalek;
+extern crate hmac;
 #[macro_use]
 extern crate log;
 extern crate rand;
+extern crate reed_solomon_erasure;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+extern crate sha2;
 
+pub mod blob;
+pub mod crdt;
+pub mod encryption;
+pub mod erasure;
+pub mod identity;
 pub mod k_bucket;
 pub mod node;
 pub mod node_id;
+pub mod persistence;
+pub mod puzzle;
+pub mod rate_limit;
+pub mod recent;
+pub mod record;
 pub mod rpc;
 pub mod storage;
+pub mod token;
+pub mod validator;
+pub mod version;
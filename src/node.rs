@@ -22,43 +22,221 @@
 //! [kademlia]: http://www.scs.stanford.edu/%7Edm/home/papers/kpos.pdf
 
 use bincode;
+use encryption::Secret;
+use erasure;
+use identity::Keypair;
 use k_bucket::{K, KBucket, KBucketEntry};
 use node_id::NodeId;
+use puzzle;
 use rand;
+use rate_limit::{RateLimiter, RateLimits, RequestClass};
+use recent::RecentMap;
+use record::{self, Announcement, MutableRecord, Record, Tombstone};
 use rpc;
 use std::io;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque, hash_map};
 use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
-use std::time::Duration;
-use storage;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use storage::{self, Key, Value};
+use token::{self, Token, TokenIssuer};
+use validator::{KindPolicy, RecordValidator};
+use version::{self, VectorClock, Versioned};
 
 /// An interface in order to handle a given message.
-pub trait MessageHandler : Send {
+pub trait MessageHandler<K = NodeId, V = Vec<u8>> : Send {
     /// Handle a given message, possibly taking ownership of it.
     ///
     /// The `message` variable is guaranteed to be non-`None`.
     ///
     /// If it's taken, other handlers won't see the message.
-    fn handle_message(&mut self, from: &SocketAddr, message: &mut Option<rpc::RPCMessage>);
+    fn handle_message(&mut self, from: &SocketAddr, message: &mut Option<rpc::RPCMessage<K, V>>);
 }
 
 /// A token identifying a message handler, which must be kept in order for the
 /// handler to be removed.
 pub struct HandlerToken(usize);
 
-/// A node in this Kademlia network.
-pub struct Node {
-    /// Id of this node.
+/// How long a lookup waits for a response before giving up on the nodes it's
+/// waiting for.
+pub const LOOKUP_RESPONSE_TIMEOUT_MS: u64 = 500;
+
+/// The maximum time a record cached along a lookup path is kept. The actual
+/// time is halved for every node that we know is closer to the key than us.
+pub const CACHE_MAX_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// The default number of records handed off to newly discovered nodes per
+/// second.
+pub const DEFAULT_HANDOFF_RATE: usize = 64;
+
+/// The default maximum time we keep a tombstone for, in seconds, see
+/// `Node::set_max_tombstone_ttl`.
+pub const DEFAULT_MAX_TOMBSTONE_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// The maximum number of records waiting to be handed off. Past it, newly
+/// discovered nodes miss out on the records that aren't queued already.
+const MAX_PENDING_HANDOFFS: usize = 1024;
+
+/// How long a node that was caught misbehaving is kept out of our routing
+/// table, in seconds.
+pub const PENALTY_SECS: u64 = 60 * 60;
+
+/// The maximum number of penalised nodes we remember. Past it, the penalty
+/// that expires the soonest is lifted early.
+const MAX_PENALISED: usize = 1024;
+
+/// The number of write tokens we keep, past which the oldest are dropped.
+const MAX_WRITE_TOKENS: usize = 1024;
+
+/// The number of nodes we remember asking for a write token, past which the
+/// oldest are forgotten.
+const MAX_TOKEN_REQUESTS: usize = 1024;
+
+/// How long we expect the response to a request carrying a write token, in
+/// seconds.
+const TOKEN_REQUEST_SECS: u64 = 30;
+
+/// How many times bigger than the request a value we send to an address that
+/// wasn't verified can be, so that we can't be used to amplify traffic towards
+/// a spoofed address.
+pub const AMPLIFICATION_FACTOR: usize = 3;
+
+/// How long a round trip to an address keeps it verified, in seconds.
+const ADDRESS_VERIFICATION_SECS: u64 = 10 * 60;
+
+/// The number of addresses we track as requested and as verified, past which
+/// the oldest are dropped.
+const MAX_TRACKED_ADDRESSES: usize = 1024;
+
+/// What a node handed off to the rest of the network when leaving it.
+#[derive(Debug, Clone)]
+pub struct LeaveReport<K = NodeId> {
+    /// The keys that were handed off, along with the nodes that acknowledged
+    /// storing them.
+    pub handed_off: Vec<(K, Vec<NodeId>)>,
+    /// The keys that no node acknowledged storing, and that are likely lost
+    /// unless some other node holds them.
+    pub lost: Vec<K>,
+    /// The number of routing table neighbours notified of our departure.
+    pub notified: usize,
+}
+
+/// A distinct record found by a quorum read, along with the nodes that served
+/// it.
+#[derive(Debug, Clone)]
+pub struct Replica<V = Vec<u8>> {
+    /// The record.
+    pub record: Record<V>,
+    /// The nodes that served this record, which may include ourselves.
+    pub nodes: Vec<KBucketEntry>,
+}
+
+/// The outcome of a lookup.
+struct LookupOutcome<V> {
+    /// The records found, along with the node that returned each of them.
+    found: Vec<(KBucketEntry, Record<V>)>,
+    /// The nodes that answered without a value.
+    missed: Vec<KBucketEntry>,
+}
+
+/// The state of one of the disjoint paths of a lookup.
+struct LookupPath {
+    /// The nodes this path will query next.
+    candidates: Vec<KBucketEntry>,
+}
+
+impl LookupPath {
+    fn new() -> Self {
+        LookupPath {
+            candidates: vec![],
+        }
+    }
+}
+
+/// A node in this Kademlia network, storing values of type `V` under keys of
+/// type `K`.
+pub struct Node<K = NodeId, V = Vec<u8>> {
+    /// Id of this node, derived from `keypair`.
     id: NodeId,
 
+    /// The keypair this node uses to sign its messages.
+    keypair: Keypair,
+
+    /// The crypto puzzle difficulty we require from other nodes.
+    puzzle_difficulty: puzzle::Difficulty,
+
+    /// Our own solution to the dynamic crypto puzzle.
+    puzzle_solution: puzzle::Solution,
+
+    /// The difficulty of the stamps we require on store requests, and put on
+    /// ours.
+    stamp_difficulty: puzzle::StampDifficulty,
+
+    /// Whether we require write tokens on store requests, and fetch them
+    /// from the nodes we store to before sending them ours.
+    require_write_tokens: bool,
+
+    /// Issues the write tokens we hand out in our lookup responses.
+    tokens: TokenIssuer,
+
+    /// The last write token each node gave us, along with when we got it.
+    write_tokens: RecentMap<NodeId, (Token, Instant)>,
+
+    /// The nodes we sent a lookup request to, whose response carries a write
+    /// token, along with when we did. Only their tokens are kept.
+    token_requests: RecentMap<NodeId, Instant>,
+
+    /// Meters the requests we get, see the `rate_limit` module.
+    rate_limiter: RateLimiter,
+
+    /// The addresses we sent requests to lately, along with the node we sent
+    /// them to. A response from that node at one of them completes a round
+    /// trip, which verifies it.
+    requested_addresses: RecentMap<SocketAddr, (NodeId, Instant)>,
+
+    /// The addresses verified lately, either by a round trip or by a request
+    /// carrying a write token, which we can send large responses to.
+    verified_addresses: RecentMap<SocketAddr, Instant>,
+
+    /// The number of disjoint paths lookups are split into.
+    disjoint_paths: usize,
+
+    /// Nodes that we caught misbehaving, like returning tampered data, and
+    /// that we refuse to add to our routing table, along with when their
+    /// penalty expires.
+    misbehaving: HashMap<NodeId, Instant>,
+
     /// Keys and values stored by this node.
-    store: storage::Store,
+    store: storage::Store<K, V>,
+
+    /// The application-defined validator for the records we store, if any.
+    validator: Option<Box<dyn RecordValidator<K, V>>>,
+
+    /// The policies for each kind of record, see `Key::kind`.
+    kind_policies: HashMap<String, KindPolicy<K, V>>,
+
+    /// The keys waiting to be handed off to newly discovered nodes that are
+    /// among the `k` closest to them, each queued once.
+    pending_handoffs: VecDeque<K>,
+
+    /// The nodes each of the keys in `pending_handoffs` goes to.
+    handoff_targets: HashMap<K, Vec<KBucketEntry>>,
+
+    /// The maximum number of records handed off per second.
+    handoff_rate: usize,
+
+    /// The maximum time we keep a tombstone for, whatever its expiration time.
+    max_tombstone_ttl: Duration,
+
+    /// The start of the current rate limiting window for handoffs, and the
+    /// number of records handed off in it.
+    handoff_window: (Instant, usize),
 
     /// The set of buckets for each bit of the key.
     buckets: Box<[KBucket]>,
 
     /// The message handlers this node owns.
-    handlers: Vec<Box<MessageHandler>>,
+    handlers: Vec<Box<dyn MessageHandler<K, V>>>,
 
     /// The UDP socket we're connecting to.
     ///
@@ -71,22 +249,60 @@ pub struct Node {
     rng: rand::OsRng,
 }
 
-impl Node {
-    /// Creates a new node, or returns an error if the function couldn't open
+impl<K, V> Node<K, V>
+    where K: Key,
+          V: Value,
+{
+    /// Creates a new node with a freshly generated identity, storing keys and
+    /// values of any type, or returns an error if the function couldn't open
     /// the OS rng, or couldn't open the appropriate port.
-    pub fn new<A>(addr: A) -> Result<Self, io::Error>
+    ///
+    /// Nodes with the default key and value types can use `Node::new` instead,
+    /// which doesn't need the types to be spelled out.
+    pub fn bind<A>(addr: A) -> Result<Self, io::Error>
+        where A: ToSocketAddrs,
+    {
+        let mut rng = rand::OsRng::new()?;
+        let keypair = Keypair::generate(&mut rng);
+        Self::bind_with_keypair(addr, keypair)
+    }
+
+    /// Creates a new node with a given identity, storing keys and values of
+    /// any type, or returns an error if the function couldn't open the OS rng,
+    /// or couldn't open the appropriate port.
+    pub fn bind_with_keypair<A>(addr: A, keypair: Keypair) -> Result<Self, io::Error>
         where A: ToSocketAddrs,
     {
         let socket = UdpSocket::bind(addr)?;
         let mut rng = rand::OsRng::new()?;
-        let id = NodeId::random(&mut rng);
+        let id = keypair.node_id();
         let mut buckets = Vec::with_capacity(160);
         for _ in 0..160 {
             buckets.push(KBucket::new());
         }
         Ok(Node {
-            id: id,
-            store: storage::Store::new(),
+            id: id.clone(),
+            keypair: keypair,
+            puzzle_difficulty: puzzle::Difficulty::default(),
+            puzzle_solution: NodeId::from_bytes([0; 20]),
+            stamp_difficulty: puzzle::StampDifficulty::default(),
+            require_write_tokens: false,
+            tokens: TokenIssuer::new(&mut rng),
+            write_tokens: RecentMap::new(MAX_WRITE_TOKENS),
+            token_requests: RecentMap::new(MAX_TOKEN_REQUESTS),
+            rate_limiter: RateLimiter::new(RateLimits::default()),
+            requested_addresses: RecentMap::new(MAX_TRACKED_ADDRESSES),
+            verified_addresses: RecentMap::new(MAX_TRACKED_ADDRESSES),
+            disjoint_paths: 1,
+            misbehaving: HashMap::new(),
+            store: storage::Store::new(id),
+            validator: None,
+            kind_policies: HashMap::new(),
+            pending_handoffs: VecDeque::new(),
+            handoff_targets: HashMap::new(),
+            handoff_rate: DEFAULT_HANDOFF_RATE,
+            max_tombstone_ttl: Duration::from_secs(DEFAULT_MAX_TOMBSTONE_TTL_SECS),
+            handoff_window: (Instant::now(), 0),
             buckets: buckets.into_boxed_slice(),
             handlers: vec![],
             socket: socket,
@@ -99,16 +315,152 @@ impl Node {
         &self.id
     }
 
+    /// Gets the keypair of the node.
+    pub fn keypair(&self) -> &Keypair {
+        &self.keypair
+    }
+
+    /// Gets our solution to the dynamic crypto puzzle, which other nodes need
+    /// in order to add us to their routing tables.
+    pub fn puzzle_solution(&self) -> &puzzle::Solution {
+        &self.puzzle_solution
+    }
+
+    /// Gets the crypto puzzle difficulty this node requires from others.
+    pub fn puzzle_difficulty(&self) -> &puzzle::Difficulty {
+        &self.puzzle_difficulty
+    }
+
+    /// Sets the crypto puzzle difficulty this node requires from other nodes,
+    /// and solves the dynamic puzzle for our own id with that difficulty.
+    ///
+    /// Note that the static puzzle can only be solved generating the keypair,
+    /// see `puzzle::generate_keypair`.
+    ///
+    /// Nodes that were already in the routing table are not verified again.
+    pub fn set_puzzle_difficulty(&mut self, difficulty: puzzle::Difficulty) {
+        if !puzzle::solves_static(&self.id, difficulty.static_bits) {
+            warn!("[{}] Our id doesn't solve the static puzzle", self.id);
+        }
+
+        self.puzzle_solution =
+            puzzle::solve_dynamic(&mut self.rng, &self.id, difficulty.dynamic_bits);
+        self.puzzle_difficulty = difficulty;
+    }
+
+    /// Sets the difficulty of the proof of work that store requests need to
+    /// carry. Our own store requests are stamped with the same difficulty, so
+    /// every node in the network is expected to use the same one.
+    ///
+    /// The base difficulty is capped to `puzzle::MAX_STAMP_BITS`.
+    pub fn set_stamp_difficulty(&mut self, mut difficulty: puzzle::StampDifficulty) {
+        if difficulty.base_bits > puzzle::MAX_STAMP_BITS {
+            warn!("[{}] Capping the stamp difficulty to {} bits",
+                  self.id, puzzle::MAX_STAMP_BITS);
+            difficulty.base_bits = puzzle::MAX_STAMP_BITS;
+        }
+        self.stamp_difficulty = difficulty;
+    }
+
+    /// Sets whether store requests need to carry a write token we gave to
+    /// their sender recently, see the `token` module.
+    ///
+    /// When enabled, we also fetch tokens from the nodes we store to before
+    /// sending them our records, so every node in the network is expected to
+    /// use the same setting.
+    pub fn set_require_write_tokens(&mut self, require: bool) {
+        self.require_write_tokens = require;
+    }
+
+    /// Sets the rate limits for the requests we get, resetting the state of
+    /// the current ones. There are no limits by default.
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.rate_limiter = RateLimiter::new(limits);
+    }
+
+    /// Gets the rate limiter for the requests we get, to look at its
+    /// statistics.
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    /// Sets the number of disjoint paths lookups are split into.
+    ///
+    /// More paths make lookups more robust against malicious nodes steering
+    /// them, at the cost of more traffic and latency. Setting this to one
+    /// gives the plain Kademlia lookup.
+    pub fn set_disjoint_paths(&mut self, paths: usize) {
+        assert!(paths > 0, "Lookups need at least one path");
+        self.disjoint_paths = paths;
+    }
+
     /// Gets a view on the buckets of the node, mostly for debugging.
     pub fn buckets(&self) -> &[KBucket] {
         &self.buckets
     }
 
     /// Go through the raw storage mechanism.
-    pub fn store(&self) -> &storage::Store {
+    pub fn store(&self) -> &storage::Store<K, V> {
         &self.store
     }
 
+    /// Sets the limits of our store. Stores from other nodes exceeding them are
+    /// refused.
+    pub fn set_storage_limits(&mut self, limits: storage::Limits) {
+        self.store.set_limits(limits);
+    }
+
+    /// Sets the validator that decides which records this node accepts,
+    /// replacing the existing one, if any.
+    pub fn set_validator(&mut self, validator: Box<dyn RecordValidator<K, V>>) {
+        self.validator = Some(validator);
+    }
+
+    /// Sets the policy for the records of a given kind, replacing the existing
+    /// one, if any.
+    pub fn set_kind_policy<S>(&mut self, kind: S, policy: KindPolicy<K, V>)
+        where S: Into<String>,
+    {
+        self.kind_policies.insert(kind.into(), policy);
+    }
+
+    /// Makes our store persistent, backed by the log at `path`, and loads the
+    /// records in it, if any, so that a restarted node keeps its replicas.
+    ///
+    /// This is meant to be called right after creating the node, since the
+    /// records stored before are dropped. The limits and eviction policy are
+    /// kept.
+    pub fn open_store<P>(&mut self, path: P) -> io::Result<()>
+        where P: AsRef<Path>,
+    {
+        let mut store = storage::Store::open(self.id.clone(), path)?;
+        store.set_limits(*self.store.limits());
+        store.set_eviction_policy(self.store.eviction_policy());
+        self.store = store;
+        Ok(())
+    }
+
+    /// Sets the policy used to evict records once our store is full.
+    pub fn set_eviction_policy(&mut self, policy: storage::EvictionPolicy) {
+        self.store.set_eviction_policy(policy);
+    }
+
+    /// Sets the maximum number of records handed off to newly discovered nodes
+    /// per second. Zero disables handoffs altogether.
+    pub fn set_handoff_rate(&mut self, records_per_second: usize) {
+        self.handoff_rate = records_per_second;
+        if records_per_second == 0 {
+            self.pending_handoffs.clear();
+            self.handoff_targets.clear();
+        }
+    }
+
+    /// Sets the maximum time we keep the tombstones we store for, so that
+    /// nobody can block a key forever with a far expiration time.
+    pub fn set_max_tombstone_ttl(&mut self, ttl: Duration) {
+        self.max_tombstone_ttl = ttl;
+    }
+
     /// Get the socket address of the node, if any, or an error.
     pub fn address(&self) -> io::Result<SocketAddr> {
         self.socket.local_addr()
@@ -124,8 +476,9 @@ impl Node {
     /// relevant for our implementation though.
     pub fn on_message(&mut self,
                       id: &NodeId,
-                      address: &SocketAddr) {
-        self.note_node(id, address);
+                      address: &SocketAddr,
+                      puzzle_solution: &puzzle::Solution) {
+        self.note_node(id, address, puzzle_solution);
     }
 
     /// Add a handler for receiving messages sent to this node.
@@ -146,13 +499,255 @@ impl Node {
     // }
 
     /// A function used to note the ID and address of a node.
+    ///
+    /// Returns false, without touching the routing table, if the id and puzzle
+    /// solution of the node don't satisfy our puzzle difficulty.
     pub fn note_node(&mut self,
                      id: &NodeId,
-                     address: &SocketAddr) {
+                     address: &SocketAddr,
+                     puzzle_solution: &puzzle::Solution)
+                     -> bool {
         trace!("[{}] note_node: {} at {:?}", self.id, id, address);
+        if self.is_penalised(id) {
+            return false;
+        }
+
+        if !puzzle::verify(id, puzzle_solution, &self.puzzle_difficulty) {
+            debug!("[{}] Refusing node {} at {:?}, puzzle not solved",
+                   self.id, id, address);
+            return false;
+        }
+
         let distance = self.id.xor(id);
+        let is_new = !self.buckets[distance.bucket_index()].iter()
+            .any(|e| e.id() == id);
         let _evicted_entry =
-            self.buckets[distance.bucket_index()].saw_node(id, address);
+            self.buckets[distance.bucket_index()].saw_node(id,
+                                                           address,
+                                                           puzzle_solution);
+        let is_known = self.buckets[distance.bucket_index()].iter()
+            .any(|e| e.id() == id);
+        if is_new && is_known && *id != self.id {
+            let entry = KBucketEntry::new(id.clone(),
+                                          address.clone(),
+                                          puzzle_solution.clone());
+            self.queue_handoffs(entry);
+        }
+        true
+    }
+
+    /// Returns whether `id` is among the `k` closest nodes to `key` that we
+    /// know about.
+    fn is_among_k_closest(&self, id: &NodeId, key: &NodeId) -> bool {
+        let distance = id.xor(key);
+        let closer = self.buckets.iter()
+            .flat_map(|bucket| bucket.iter())
+            .filter(|e| e.id() != id && e.id().xor(key) < distance)
+            .take(K)
+            .count();
+        closer < K
+    }
+
+    /// Queues the stored records for which a newly discovered node is among
+    /// the `k` closest nodes to be handed off to it, as described in section
+    /// 2.5 of the paper, and hands off as many as the rate limit allows.
+    ///
+    /// Records we only cache are not handed off, they'll expire soon anyway.
+    /// Nor are they once `MAX_PENDING_HANDOFFS` records are waiting, unless
+    /// they're queued already.
+    fn queue_handoffs(&mut self, node: KBucketEntry) {
+        if self.handoff_rate == 0 {
+            return;
+        }
+
+        let keys = self.store.iter()
+            .filter(|&(_, entry)| entry.expires_at.is_none())
+            .filter(|&(key, _)| self.is_among_k_closest(node.id(), &key.to_id()))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        for key in keys {
+            let targets = match self.handoff_targets.entry(key.clone()) {
+                hash_map::Entry::Occupied(targets) => targets.into_mut(),
+                hash_map::Entry::Vacant(targets) => {
+                    if self.pending_handoffs.len() >= MAX_PENDING_HANDOFFS {
+                        continue;
+                    }
+                    self.pending_handoffs.push_back(key.clone());
+                    targets.insert(vec![])
+                }
+            };
+            if targets.len() < K && !targets.iter().any(|t| t.id() == node.id()) {
+                trace!("[{}] Handing off {:?} to {}", self.id, key, node.id());
+                targets.push(node.clone());
+            }
+        }
+
+        self.flush_handoffs();
+    }
+
+    /// Hands off as many of the queued records as the rate limit allows.
+    ///
+    /// This is done automatically as new nodes are discovered, but it may be
+    /// called periodically too, so that the queue drains even if the network
+    /// goes quiet.
+    ///
+    /// If write tokens are required, records are only handed off to nodes we
+    /// hold a token for. The others are asked for one, and their handoffs are
+    /// put back at the end of the queue, until the next flush after the
+    /// response brings the token.
+    pub fn flush_handoffs(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.handoff_window.0) >= Duration::from_secs(1) {
+            self.handoff_window = (now, 0);
+        }
+
+        let mut deferred = 0;
+        while self.handoff_window.1 < self.handoff_rate &&
+              deferred < self.pending_handoffs.len() {
+            let key = match self.pending_handoffs.front() {
+                Some(key) => key.clone(),
+                None => break,
+            };
+
+            let record = self.store.peek(&key).cloned();
+            let node = self.handoff_targets.get(&key)
+                .expect("Queued handoffs should have targets")
+                .last()
+                .cloned();
+            let (record, node) = match (record, node) {
+                (Some(record), Some(node)) => (record, node),
+                _ => {
+                    self.pending_handoffs.pop_front();
+                    self.handoff_targets.remove(&key);
+                    continue;
+                }
+            };
+
+            if self.require_write_tokens && self.write_token_for(node.id()).is_none() {
+                let asked_at = self.token_requests.get(node.id()).cloned();
+                let max_age = Duration::from_secs(TOKEN_REQUEST_SECS);
+                if asked_at.map_or(false, |at| at.elapsed() >= max_age) {
+                    // It never answered, so move on to the other targets.
+                    debug!("[{}] Giving up on handing off {:?} to {}",
+                           self.id, key, node.id());
+                    self.token_requests.remove(node.id());
+                    let done = {
+                        let targets = self.handoff_targets.get_mut(&key)
+                            .expect("Queued handoffs should have targets");
+                        targets.pop();
+                        targets.is_empty()
+                    };
+                    if done {
+                        self.pending_handoffs.pop_front();
+                        self.handoff_targets.remove(&key);
+                    }
+                    continue;
+                }
+                if asked_at.is_none() {
+                    self.handoff_window.1 += 1;
+                    let message = rpc::RequestKind::FindValue(key.clone(), None);
+                    let message = rpc::MessageKind::Request(message);
+                    let message = rpc::RPCMessage::new(self.id.clone(), message);
+                    if let Err(err) = self.send_message(node.id().clone(),
+                                                        node.address().clone(),
+                                                        message) {
+                        debug!("[{}] Failed to ask {} for a write token: {:?}",
+                               self.id, node.id(), err);
+                    }
+                }
+                self.pending_handoffs.pop_front();
+                self.pending_handoffs.push_back(key);
+                deferred += 1;
+                continue;
+            }
+
+            let done = {
+                let targets = self.handoff_targets.get_mut(&key)
+                    .expect("Queued handoffs should have targets");
+                targets.pop();
+                targets.is_empty()
+            };
+            if done {
+                self.pending_handoffs.pop_front();
+                self.handoff_targets.remove(&key);
+            }
+
+            self.handoff_window.1 += 1;
+            let message = self.store_request(node.id(), key, record, None);
+            let message = rpc::MessageKind::Request(message);
+            let message = rpc::RPCMessage::new(self.id.clone(), message);
+            if let Err(err) = self.send_message(node.id().clone(),
+                                                node.address().clone(),
+                                                message) {
+                debug!("[{}] Failed to hand off to {}: {:?}",
+                       self.id, node.id(), err);
+            }
+        }
+    }
+
+    /// Penalises a node that was caught misbehaving, removing it from the
+    /// routing table and refusing to add it back for `PENALTY_SECS`.
+    pub fn penalise(&mut self, id: &NodeId) {
+        debug!("[{}] Penalising {}", self.id, id);
+        let distance = self.id.xor(id);
+        self.buckets[distance.bucket_index()].remove(id);
+
+        if self.misbehaving.len() >= MAX_PENALISED && !self.misbehaving.contains_key(id) {
+            let soonest = self.misbehaving.iter()
+                .min_by_key(|&(_, until)| *until)
+                .map(|(id, _)| id.clone());
+            if let Some(soonest) = soonest {
+                self.misbehaving.remove(&soonest);
+            }
+        }
+        let until = Instant::now() + Duration::from_secs(PENALTY_SECS);
+        self.misbehaving.insert(id.clone(), until);
+    }
+
+    /// Returns whether `address` was verified lately, so that responses to it
+    /// don't need to respect `AMPLIFICATION_FACTOR`.
+    fn is_verified(&self, address: &SocketAddr) -> bool {
+        let max_age = Duration::from_secs(ADDRESS_VERIFICATION_SECS);
+        self.verified_addresses.get(address).map_or(false, |at| at.elapsed() < max_age)
+    }
+
+    /// Drops the farthest of `nodes` from `target` until a response that's
+    /// `empty` without them is at most `AMPLIFICATION_FACTOR` times bigger than
+    /// the request, unless `address` is verified, and returns whether any were
+    /// dropped. The rest can be asked for again with the write token the
+    /// response carries.
+    fn truncate_response(&self,
+                         address: &SocketAddr,
+                         target: &NodeId,
+                         request_size: usize,
+                         empty: &rpc::ResponseKind<K, V>,
+                         nodes: &mut Vec<KBucketEntry>)
+                         -> bool {
+        if self.is_verified(address) {
+            return false;
+        }
+
+        nodes.sort_by_key(|e| target.xor(e.id()));
+        let mut size = rpc::envelope_size() + bincode::serialized_size(empty) as usize;
+        let fitting = nodes.iter()
+            .take_while(|node| {
+                size += bincode::serialized_size(*node) as usize;
+                size <= AMPLIFICATION_FACTOR * request_size
+            })
+            .count();
+        if fitting == nodes.len() {
+            return false;
+        }
+        debug!("[{}] Sending {} of {} nodes to unverified {}",
+               self.id, fitting, nodes.len(), address);
+        nodes.truncate(fitting);
+        true
+    }
+
+    /// Returns whether `id` is serving a penalty, see `penalise`.
+    pub fn is_penalised(&self, id: &NodeId) -> bool {
+        self.misbehaving.get(id).map_or(false, |until| *until > Instant::now())
     }
 
     /// Set the read timeout of the underlying socket.
@@ -166,18 +761,50 @@ impl Node {
     ///
     /// Returns a result, either success, with the socket address we received
     /// the message from, or an error.
-    pub fn recv_message(&mut self) -> io::Result<(SocketAddr, rpc::RPCMessage)> {
+    ///
+    /// Messages whose signature doesn't verify, or whose sender id doesn't
+    /// match their public key, are returned as an `InvalidData` error, and
+    /// the sender is not added to the routing table. So are messages over the
+    /// rate limits of their source address, which are checked beforehand.
+    pub fn recv_message(&mut self) -> io::Result<(SocketAddr, rpc::RPCMessage<K, V>)> {
         let mut dest = vec![0; rpc::RPC_MESSAGE_MAX_SIZE];
 
         let (bytes_read, source) = self.socket.recv_from(&mut dest)?;
-        let message: rpc::RPCMessage =
+        let message: rpc::RPCMessage<K, V> =
             match bincode::deserialize(&dest[..bytes_read]) {
                 Ok(m) => m,
                 Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
             };
 
+        let class = match message.kind {
+            rpc::MessageKind::Request(ref request) => RequestClass::of(request),
+            rpc::MessageKind::Response(..) => RequestClass::Response,
+        };
+        if let Err(scope) = self.rate_limiter.check_ip(&source.ip(), class) {
+            debug!("[{}] Throttled {:?} from {} by the {:?} limit",
+                   self.id, class, source, scope);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Throttled message"));
+        }
+
+        if !message.verify() {
+            debug!("Got message with an invalid signature from {:?}", source);
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "Invalid message signature"));
+        }
+
         debug!("Got message {:?}", message);
-        self.note_node(&message.sender, &source);
+        // Before noting the sender, which may send it requests of our own.
+        self.note_write_token(&message);
+        self.note_node(&message.sender, &source, &message.puzzle_solution);
+        if let rpc::MessageKind::Response(..) = message.kind {
+            let max_age = Duration::from_secs(ADDRESS_VERIFICATION_SECS);
+            let requested = self.requested_addresses.get(&source).map_or(false, |entry| {
+                entry.0 == message.sender && entry.1.elapsed() < max_age
+            });
+            if requested {
+                self.verified_addresses.insert(source, Instant::now());
+            }
+        }
         Ok((source, message))
     }
 
@@ -212,7 +839,7 @@ impl Node {
 
             after_message(msg.is_some());
 
-            if let Some(rpc::RPCMessage { kind, sender }) = msg {
+            if let Some(rpc::RPCMessage { kind, sender, .. }) = msg {
                 match kind {
                     rpc::MessageKind::Request(request_kind) => {
                         let _ =
@@ -278,60 +905,212 @@ impl Node {
     }
 
     /// Handles a given request message.
+    ///
+    /// Requests over the rate limits of their sender or the global ones are
+    /// dropped, except store requests, which get a `StoreError::Throttled`
+    /// response so that the sender backs off. The limits of their source
+    /// address are checked by `recv_message`.
     pub fn handle_request(&mut self,
-                          request: rpc::RequestKind,
+                          request: rpc::RequestKind<K, V>,
                           sender: NodeId,
                           source: SocketAddr)
                           -> io::Result<()> {
+        self.tokens.rotate_if_needed(&mut self.rng);
+
+        let class = RequestClass::of(&request);
+        if let Err(scope) = self.rate_limiter.check(&sender, class) {
+            debug!("[{}] Throttled {:?} from {} at {} by the {:?} limit",
+                   self.id, class, sender, source, scope);
+            if let rpc::RequestKind::Store(key, ..) = request {
+                let msg = rpc::ResponseKind::Store(key, Err(storage::StoreError::Throttled));
+                let msg = rpc::MessageKind::Response(msg);
+                let msg = rpc::RPCMessage::new(self.id.clone(), msg);
+                return self.send_message(sender, source, msg);
+            }
+            return Ok(());
+        }
+
+        let request_size = rpc::envelope_size() + bincode::serialized_size(&request) as usize;
         match request {
             rpc::RequestKind::Ping => {
                 let msg = rpc::MessageKind::Response(rpc::ResponseKind::Pong);
                 let msg = rpc::RPCMessage::new(self.id.clone(), msg);
                 self.send_message(sender, source, msg)
             }
-            rpc::RequestKind::FindNode(node_id) => {
+            rpc::RequestKind::FindNode(node_id, token) => {
                 if node_id == self.id {
                     // That's quite a nonsensical request, since they needed our
                     // address and ID to find us.
                     return Ok(());
                 }
+                if token.map_or(false, |token| self.tokens.verify(&source.ip(), &token)) {
+                    self.verified_addresses.insert(source, Instant::now());
+                }
 
-                let nodes = self.find_k_known_nodes_closer_to(&node_id);
-                let response = rpc::ResponseKind::FindNode(nodes);
+                let mut nodes = self.find_k_known_nodes_closer_to(&node_id);
+                let token = self.tokens.issue(&source.ip());
+                let empty: rpc::ResponseKind<K, V> =
+                    rpc::ResponseKind::FindNode(vec![], token.clone());
+                self.truncate_response(&source, &node_id, request_size, &empty, &mut nodes);
+                let response = rpc::ResponseKind::FindNode(nodes, token);
                 let msg = rpc::MessageKind::Response(response);
                 let msg = rpc::RPCMessage::new(self.id.clone(), msg);
 
                 self.send_message(sender, source, msg)
             }
-            rpc::RequestKind::Store(key, val) => {
-                self.store.insert(key, val);
-                Ok(())
+            rpc::RequestKind::Store(key, record, cas, stamp, token) => {
+                let has_token = token.map_or(false, |token| {
+                    self.tokens.verify(&source.ip(), &token)
+                });
+                let result = if self.require_write_tokens && !has_token {
+                    Err(storage::StoreError::InvalidToken)
+                } else if puzzle::verify_stamp(&sender,
+                                                     &self.id,
+                                                     &key,
+                                                     &record,
+                                                     stamp,
+                                                     &self.stamp_difficulty) {
+                    self.store_record(key.clone(), record, cas, Some(&source), None)
+                } else {
+                    let size = bincode::serialized_size(&record) as usize;
+                    let bits = self.stamp_difficulty.bits_for(size);
+                    Err(storage::StoreError::InsufficientWork(bits))
+                };
+                if let Err(ref err) = result {
+                    debug!("[{}] Refused store for {:?}: {:?}", self.id, key, err);
+                }
+
+                let msg = rpc::ResponseKind::Store(key, result);
+                let msg = rpc::MessageKind::Response(msg);
+                let msg = rpc::RPCMessage::new(self.id.clone(), msg);
+                self.send_message(sender, source, msg)
             }
-            rpc::RequestKind::FindValue(key) => {
+            rpc::RequestKind::FindValue(key, token) => {
+                if token.map_or(false, |token| self.tokens.verify(&source.ip(), &token)) {
+                    self.verified_addresses.insert(source, Instant::now());
+                }
+
+                let token = self.tokens.issue(&source.ip());
+                let verify_address = rpc::FindValueResponse::VerifyAddress(key.clone());
                 let response = match self.store.get(&key) {
                     Some(v) => {
-                        rpc::FindValueResponse::Value(key, v.clone())
+                        let mut v = v.clone();
+                        v.remove_expired();
+                        rpc::FindValueResponse::Value(key, v)
                     }
                     None => {
-                        let nodes = self.find_k_known_nodes_closer_to(&key);
-                        rpc::FindValueResponse::CloserNodes(nodes)
+                        let target = key.to_id();
+                        let mut nodes = self.find_k_known_nodes_closer_to(&target);
+                        let empty: rpc::ResponseKind<K, V> = rpc::ResponseKind::FindValue(
+                            rpc::FindValueResponse::CloserNodes(key.clone(), vec![], false),
+                            token.clone());
+                        let truncated = self.truncate_response(&source,
+                                                               &target,
+                                                               request_size,
+                                                               &empty,
+                                                               &mut nodes);
+                        rpc::FindValueResponse::CloserNodes(key, nodes, truncated)
                     }
                 };
 
-                let msg = rpc::ResponseKind::FindValue(response);
+                // Values can't be cut short, so the sender needs to prove its
+                // address with the token first.
+                let mut msg = rpc::ResponseKind::FindValue(response, token.clone());
+                let response_size = rpc::envelope_size() + bincode::serialized_size(&msg) as usize;
+                if !self.is_verified(&source) &&
+                   response_size > AMPLIFICATION_FACTOR * request_size {
+                    debug!("[{}] Asking {} at {} to verify its address",
+                           self.id, sender, source);
+                    msg = rpc::ResponseKind::FindValue(verify_address, token);
+                }
                 let msg = rpc::MessageKind::Response(msg);
                 let msg = rpc::RPCMessage::new(self.id.clone(), msg);
                 self.send_message(sender, source, msg)
             }
+            rpc::RequestKind::Leave => {
+                debug!("[{}] {} is leaving the network", self.id, sender);
+                let distance = self.id.xor(&sender);
+                self.buckets[distance.bucket_index()].remove(&sender);
+                Ok(())
+            }
+            rpc::RequestKind::Cache(key, record, stamp, token) => {
+                // Never let a cached copy replace a record we already hold.
+                if self.store.peek(&key).is_some() {
+                    return Ok(());
+                }
+
+                let has_token = token.map_or(false, |token| {
+                    self.tokens.verify(&source.ip(), &token)
+                });
+                if self.require_write_tokens && !has_token {
+                    debug!("[{}] Refused to cache {:?}: invalid token", self.id, key);
+                    return Ok(());
+                }
+
+                if !puzzle::verify_stamp(&sender,
+                                         &self.id,
+                                         &key,
+                                         &record,
+                                         stamp,
+                                         &self.stamp_difficulty) {
+                    debug!("[{}] Refused to cache {:?}: insufficient work", self.id, key);
+                    return Ok(());
+                }
+
+                let ttl = self.cache_ttl_for(&key.to_id());
+                let result =
+                    self.store_record(key.clone(), record, None, Some(&source), Some(ttl));
+                if let Err(ref err) = result {
+                    debug!("[{}] Refused to cache {:?}: {:?}", self.id, key, err);
+                }
+                Ok(())
+            }
         }
     }
 
-    /// Send a message to a given node.
+    /// Computes how long to cache a record for a key with the given id.
+    ///
+    /// The time is halved for every node in our routing table that's closer to
+    /// the key than us, so that copies far from the key go away soon.
+    fn cache_ttl_for(&self, id: &NodeId) -> Duration {
+        let distance = self.id.xor(id);
+        let closer = self.buckets.iter()
+            .flat_map(|bucket| bucket.iter())
+            .filter(|e| e.id().xor(id) < distance)
+            .count();
+        Duration::from_secs(CACHE_MAX_TTL_SECS >> ::std::cmp::min(closer, 63))
+    }
+
+    /// Send a message to a given node, signing it with our keypair.
+    ///
+    /// Store and lookup requests carry the last write token the node gave us,
+    /// if it's still fresh.
     pub fn send_message(&mut self,
-                        _id: NodeId,
+                        id: NodeId,
                         address: SocketAddr,
-                        message: rpc::RPCMessage)
+                        mut message: rpc::RPCMessage<K, V>)
                         -> io::Result<()> {
+        match message.kind {
+            rpc::MessageKind::Request(rpc::RequestKind::Store(.., ref mut token)) |
+            rpc::MessageKind::Request(rpc::RequestKind::Cache(.., ref mut token)) => {
+                *token = self.write_token_for(&id).cloned();
+            }
+            rpc::MessageKind::Request(rpc::RequestKind::FindValue(_, ref mut token)) => {
+                *token = self.write_token_for(&id).cloned();
+                self.token_requests.insert(id.clone(), Instant::now());
+            }
+            rpc::MessageKind::Request(rpc::RequestKind::FindNode(_, ref mut token)) => {
+                *token = self.write_token_for(&id).cloned();
+                self.token_requests.insert(id.clone(), Instant::now());
+            }
+            _ => {}
+        }
+        if let rpc::MessageKind::Request(..) = message.kind {
+            self.requested_addresses.insert(address, (id.clone(), Instant::now()));
+        }
+        message.puzzle_solution = self.puzzle_solution.clone();
+        message.sign(&self.keypair);
+
         let mut dest = vec![];
         match bincode::serialize_into(&mut dest,
                                       &message,
@@ -348,109 +1127,1012 @@ impl Node {
         self.socket.send_to(&dest, address).map(|_| {})
     }
 
-    /// Sends a store message, using the given key and value.
-    ///
-    /// Returns the results of the IO operations.
-    pub fn try_store(&mut self,
-                     key: storage::Key,
-                     value: storage::Value) {
-        self.store.insert(key.clone(), value.clone());
+    /// Keeps the write token that comes with a lookup response, if any, as
+    /// long as we asked its sender for one lately.
+    fn note_write_token(&mut self, message: &rpc::RPCMessage<K, V>) {
+        let token = match message.kind {
+            rpc::MessageKind::Response(rpc::ResponseKind::FindNode(_, ref token)) |
+            rpc::MessageKind::Response(rpc::ResponseKind::FindValue(_, ref token)) => token,
+            _ => return,
+        };
 
-        let nodes = self.find_k_known_nodes_closer_to(&key);
-        if nodes.is_empty() {
+        if !self.is_awaiting_token(&message.sender) {
+            debug!("[{}] Ignoring unrequested write token from {}", self.id, message.sender);
             return;
         }
+        self.token_requests.remove(&message.sender);
+        self.write_tokens.insert(message.sender.clone(), (token.clone(), Instant::now()));
+    }
 
-        let message = rpc::MessageKind::Request(rpc::RequestKind::Store(key, value));
-        let message = rpc::RPCMessage::new(self.id().clone(), message);
+    /// Returns whether we sent `id` a lookup request lately, whose response
+    /// we're still waiting for.
+    fn is_awaiting_token(&self, id: &NodeId) -> bool {
+        let max_age = Duration::from_secs(TOKEN_REQUEST_SECS);
+        self.token_requests.get(id).map_or(false, |at| at.elapsed() < max_age)
+    }
 
-        for node in nodes {
-            match self.send_message(node.id().clone(),
-                                    node.address().clone(),
-                                    message.clone()) {
-                Ok(()) => {}
-                Err(err) => {
-                    error!("Failed to send store request to {:?}, {:?}",
-                           node.id(), err);
-                }
-            }
+    /// Gets the write token `id` gave us, unless it may have expired already.
+    fn write_token_for(&self, id: &NodeId) -> Option<&Token> {
+        let max_age = Duration::from_secs(token::SECRET_ROTATION_SECS);
+        match self.write_tokens.get(id) {
+            Some(&(ref token, at)) if at.elapsed() < max_age => Some(token),
+            _ => None,
         }
     }
 
-    /// Tries to find a key in the map.
+    /// Asks the nodes we don't have a fresh write token for to look up `key`,
+    /// and waits for their responses, which carry one.
     ///
-    /// Returns an error in the case of an error receiving a message, otherwise
-    /// returns the value if found.
-    pub fn find(&mut self,
-                k: storage::Key)
-                -> io::Result<Option<storage::Value>> {
-        trace!("[{}] Looking at {:?}", self.id(), k);
+    /// Does nothing unless write tokens are required, see
+    /// `set_require_write_tokens`.
+    fn fetch_write_tokens(&mut self, key: &K, nodes: &[KBucketEntry]) -> io::Result<()> {
+        let targets = nodes.iter()
+            .map(|node| (key.clone(), node.clone()))
+            .collect::<Vec<_>>();
+        self.fetch_write_tokens_for(&targets)
+    }
 
-        if let Some(r) = self.store.get(&k) {
-            return Ok(Some(r.clone()));
+    /// Like `fetch_write_tokens`, but asking each node to look up its own key,
+    /// so that the tokens of every node we're going to store to can be fetched
+    /// at once.
+    ///
+    /// Other responses we get in the meantime are dropped, so this needs to
+    /// happen before sending any requests whose responses we wait for.
+    fn fetch_write_tokens_for(&mut self, targets: &[(K, KBucketEntry)]) -> io::Result<()> {
+        if !self.require_write_tokens {
+            return Ok(());
         }
 
-        let mut nodes_seen = HashSet::new();
-
-        let old_timeout = self.socket.read_timeout()?;
-        self.socket.set_read_timeout(None)?;
-
-        let request =
-            rpc::MessageKind::Request(rpc::RequestKind::FindValue(k.clone()));
-        let request =
-            rpc::RPCMessage::new(self.id.clone(), request);
-        let mut nodes_to_try_from_last_round = Vec::new();
-        loop {
-            let nodes =
-                self.find_k_known_nodes_closer_to_not_in(&k, &nodes_seen);
-
-            trace!("[{}] closer_nodes: {:?}, from_last: {:?}", self.id(),
-                   nodes, nodes_to_try_from_last_round);
-
-            if nodes.is_empty() && nodes_to_try_from_last_round.is_empty() {
-                self.socket.set_read_timeout(old_timeout)?;
-                return Ok(None);
+        let mut pending = HashSet::new();
+        for &(ref key, ref node) in targets {
+            if pending.contains(node.id()) || self.write_token_for(node.id()).is_some() {
+                continue;
             }
-            for node in nodes.into_iter().chain(nodes_to_try_from_last_round.into_iter()) {
-                nodes_seen.insert(node.id().clone());
-                let _ = self.send_message(node.id().clone(),
-                                          node.address().clone(),
-                                          request.clone());
+            let message = rpc::RequestKind::FindValue(key.clone(), None);
+            let message = rpc::MessageKind::Request(message);
+            let message = rpc::RPCMessage::new(self.id.clone(), message);
+            if self.send_message(node.id().clone(), node.address().clone(), message).is_ok() {
+                pending.insert(node.id().clone());
             }
+        }
 
-            nodes_to_try_from_last_round = Vec::new();
+        let old_timeout = self.socket.read_timeout()?;
+        let timeout = Duration::from_millis(LOOKUP_RESPONSE_TIMEOUT_MS);
+        self.socket.set_read_timeout(Some(timeout))?;
+        while !pending.is_empty() {
+            let (source, message) = match self.recv_message() {
+                Ok(message) => message,
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => {
+                    self.socket.set_read_timeout(old_timeout)?;
+                    return Err(e);
+                }
+            };
 
-            // FIXME(emilio): This blocks, which is suboptimal, and assumes that
-            // a timeout hasn't been reached.
-            //
-            // A good step would be just recv_message with a timeout. Even
-            // better would be making a generic "observer" interface that
-            // observed new messages and resolved a future with the value if
-            // found...
-            let (source, message) = self.recv_message()?;
             match message.kind {
                 rpc::MessageKind::Request(r) => {
                     let _ = self.handle_request(r, message.sender, source);
                 }
-                rpc::MessageKind::Response(rpc::ResponseKind::FindValue(fvr)) => {
-                    match fvr {
-                        rpc::FindValueResponse::Value(key, v) => {
-                            trace!("Got Value({:?}, {:?})", key, v);
-                            if key == k {
-                                self.socket.set_read_timeout(old_timeout)?;
-                                return Ok(Some(v))
-                            }
-                            debug!("Received stale value for key {:?}", key);
-                        }
-                        // FIXME(emilio): This should probably reply w/ the key
-                        // too to avoid stale responses?
-                        rpc::FindValueResponse::CloserNodes(nodes) => {
-                            nodes_to_try_from_last_round = nodes;
-                        }
-                    }
+                rpc::MessageKind::Response(rpc::ResponseKind::FindValue(..)) => {
+                    pending.remove(&message.sender);
                 }
-                rpc::MessageKind::Response(..) => {
-                    // FIXME(emilio): With the non-blocking, observer-based
+                _ => {}
+            }
+        }
+        self.socket.set_read_timeout(old_timeout)
+    }
+
+    /// Returns whether our validator, if any, accepts a record.
+    fn is_valid(&self, key: &K, record: &Record<V>) -> bool {
+        self.validate(key, record).is_ok()
+    }
+
+    /// Gets the policy for the kind of records stored under `key`, if any.
+    fn kind_policy(&self, key: &K) -> Option<&KindPolicy<K, V>> {
+        key.kind().and_then(|kind| self.kind_policies.get(kind))
+    }
+
+    /// Runs our validator, and the one for the kind of the record, if any.
+    fn validate(&self, key: &K, record: &Record<V>) -> Result<(), String> {
+        if let Some(ref validator) = self.validator {
+            validator.validate(key, record)?;
+        }
+        if let Some(validator) = self.kind_policy(key).and_then(|p| p.validator.as_ref()) {
+            validator.validate(key, record)?;
+        }
+        Ok(())
+    }
+
+    /// Stores a record in our own store, if it can replace the existing one,
+    /// and there's room for it.
+    ///
+    /// `source` is the address of the node that sent the record, or `None` if
+    /// the record comes from ourselves. `ttl` is how long to keep the record
+    /// for, if it shouldn't be kept indefinitely, or until the record expires
+    /// on its own.
+    fn store_record(&mut self,
+                    key: K,
+                    record: Record<V>,
+                    cas: Option<u64>,
+                    source: Option<&SocketAddr>,
+                    ttl: Option<Duration>)
+                    -> Result<(), storage::StoreError> {
+        record.check_update(&key, self.store.peek(&key), cas)?;
+        self.validate(&key, &record).map_err(storage::StoreError::Rejected)?;
+        if let Some(ref validator) = self.validator {
+            if let Some(existing) = self.store.peek(&key) {
+                let records = [existing.clone(), record.clone()];
+                if validator.select(&key, &records) == 0 {
+                    return Err(storage::StoreError::NotSelected);
+                }
+            }
+        }
+
+        let mut ttl = ttl.or_else(|| record.expires_in());
+        if let Some(policy) = self.kind_policy(&key) {
+            if let Some(max) = policy.max_record_size {
+                if bincode::serialized_size(&record) as usize > max {
+                    return Err(storage::StoreError::TooLarge(max));
+                }
+            }
+            ttl = match (ttl, policy.ttl) {
+                (Some(a), Some(b)) => Some(::std::cmp::min(a, b)),
+                (a, b) => a.or(b),
+            };
+        }
+        if let Record::Tombstone(..) = record {
+            let max = self.max_tombstone_ttl;
+            ttl = Some(ttl.map_or(max, |ttl| ::std::cmp::min(ttl, max)));
+        }
+
+        let source = storage::Source::of(source);
+        let record = record.merge(self.store.peek(&key));
+        let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        self.store.insert(key, record, source, expires_at)
+    }
+
+    /// Sends a store message, using the given key and value.
+    pub fn try_store(&mut self,
+                     key: K,
+                     value: V) {
+        if let Err(err) = self.try_store_record(key, Record::Plain(value), None) {
+            error!("[{}] Failed to store value: {:?}", self.id, err);
+        }
+    }
+
+    /// Announces ourselves under a key for `ttl`, with an arbitrary value, like
+    /// the address of a service.
+    ///
+    /// Our previous announcement under the same key, if any, is replaced.
+    pub fn announce(&mut self,
+                    key: K,
+                    value: V,
+                    ttl: Duration)
+                    -> Result<(), storage::StoreError> {
+        let announcement = Announcement::new(&self.keypair, &key, value, ttl);
+        let record = Record::Announcements(vec![announcement]);
+        self.try_store_record(key, record, None)
+    }
+
+    /// Stores a new version of a value, descending from the versions in
+    /// `context`, which is usually `version::context` of the siblings the
+    /// writer read and reconciled, or an empty clock for a new value.
+    ///
+    /// The nodes storing the key discard the versions the new one supersedes,
+    /// and keep the concurrent ones as siblings. Returns the clock of the new
+    /// version.
+    pub fn put_versioned(&mut self,
+                         key: K,
+                         value: V,
+                         context: VectorClock)
+                         -> Result<VectorClock, storage::StoreError> {
+        let mut clock = context;
+        clock.increment(&self.id);
+        let version = Versioned {
+            clock: clock.clone(),
+            value: value,
+        };
+        self.try_store_record(key, Record::Versioned(vec![version]), None)?;
+        Ok(clock)
+    }
+
+    /// Deletes the record under a key, publishing a tombstone that replaces it
+    /// in the nodes storing it, and blocks older versions of it from being
+    /// stored again until it expires.
+    ///
+    /// See `record::Tombstone` for who can delete which records.
+    pub fn delete(&mut self,
+                  key: K,
+                  tombstone: Tombstone)
+                  -> Result<(), storage::StoreError> {
+        self.try_store_record(key, Record::Tombstone(tombstone), None)
+    }
+
+    /// Builds a store request for a record to `receiver`, stamped with the
+    /// proof of work our own stamp difficulty requires.
+    fn store_request(&self,
+                     receiver: &NodeId,
+                     key: K,
+                     record: Record<V>,
+                     cas: Option<u64>)
+                     -> rpc::RequestKind<K, V> {
+        let stamp = puzzle::solve_stamp(&self.id,
+                                        receiver,
+                                        &key,
+                                        &record,
+                                        &self.stamp_difficulty);
+        rpc::RequestKind::Store(key, record, cas, stamp, None)
+    }
+
+    /// Stores a record locally, and sends it to the `k` closest nodes we know
+    /// about.
+    fn try_store_record(&mut self,
+                        key: K,
+                        record: Record<V>,
+                        cas: Option<u64>)
+                        -> Result<(), storage::StoreError> {
+        self.store_record(key.clone(), record.clone(), cas, None, None)?;
+
+        let nodes = self.find_k_known_nodes_closer_to(&key.to_id());
+        if nodes.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(err) = self.fetch_write_tokens(&key, &nodes) {
+            debug!("[{}] Failed to fetch write tokens: {:?}", self.id, err);
+        }
+
+        for node in nodes {
+            let message = self.store_request(node.id(), key.clone(), record.clone(), cas);
+            let message = rpc::MessageKind::Request(message);
+            let message = rpc::RPCMessage::new(self.id().clone(), message);
+            match self.send_message(node.id().clone(),
+                                    node.address().clone(),
+                                    message) {
+                Ok(()) => {}
+                Err(err) => {
+                    error!("Failed to send store request to {:?}, {:?}",
+                           node.id(), err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Leaves the network gracefully, handing off every record we store to the
+    /// `k` closest nodes we know about for its key, and waiting for them to
+    /// acknowledge it.
+    ///
+    /// If `notify_neighbours` is true, every node in our routing table is told
+    /// that we're leaving, so that they drop us right away instead of waiting
+    /// for us to time out.
+    ///
+    /// Records we only cache are not handed off.
+    pub fn leave(mut self, notify_neighbours: bool) -> io::Result<LeaveReport<K>> {
+        let keys = self.store.iter()
+            .filter(|&(_, entry)| entry.expires_at.is_none())
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        // Fetch every token before sending any store, since the acks of the
+        // stores would be dropped while fetching.
+        let targets = keys.iter()
+            .map(|key| (key.clone(), self.find_k_known_nodes_closer_to(&key.to_id())))
+            .collect::<Vec<_>>();
+        let token_targets = targets.iter()
+            .flat_map(|&(ref key, ref nodes)| nodes.iter().map(move |n| (key.clone(), n.clone())))
+            .collect::<Vec<_>>();
+        if let Err(err) = self.fetch_write_tokens_for(&token_targets) {
+            debug!("[{}] Failed to fetch write tokens: {:?}", self.id, err);
+        }
+
+        let mut acks = HashMap::new();
+        let mut expected = 0;
+        for (key, nodes) in targets {
+            let record = match self.store.peek(&key) {
+                Some(record) => record.clone(),
+                None => continue,
+            };
+
+            acks.insert(key.clone(), vec![]);
+            for node in nodes {
+                let message =
+                    self.store_request(node.id(), key.clone(), record.clone(), None);
+                let message = rpc::MessageKind::Request(message);
+                let message = rpc::RPCMessage::new(self.id.clone(), message);
+                match self.send_message(node.id().clone(),
+                                        node.address().clone(),
+                                        message) {
+                    Ok(()) => expected += 1,
+                    Err(err) => {
+                        debug!("[{}] Failed to hand off {:?} to {}: {:?}",
+                               self.id, key, node.id(), err);
+                    }
+                }
+            }
+        }
+
+        let timeout = Duration::from_millis(LOOKUP_RESPONSE_TIMEOUT_MS);
+        self.socket.set_read_timeout(Some(timeout))?;
+        let mut received = 0;
+        while received < expected {
+            let message = match self.recv_message() {
+                Ok((_, message)) => message,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => break,
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => continue,
+                Err(e) => return Err(e),
+            };
+
+            let response = match message.kind {
+                rpc::MessageKind::Response(rpc::ResponseKind::Store(k, r)) => (k, r),
+                _ => continue,
+            };
+
+            received += 1;
+            if let (key, Ok(())) = response {
+                if let Some(nodes) = acks.get_mut(&key) {
+                    if !nodes.contains(&message.sender) {
+                        nodes.push(message.sender);
+                    }
+                }
+            }
+        }
+
+        let mut report = LeaveReport {
+            handed_off: vec![],
+            lost: vec![],
+            notified: 0,
+        };
+
+        for (key, nodes) in acks {
+            if nodes.is_empty() {
+                warn!("[{}] Nobody took {:?} over", self.id, key);
+                report.lost.push(key);
+            } else {
+                report.handed_off.push((key, nodes));
+            }
+        }
+
+        if notify_neighbours {
+            let neighbours = self.buckets.iter()
+                .flat_map(|bucket| bucket.iter())
+                .cloned()
+                .collect::<Vec<_>>();
+            let message = rpc::MessageKind::Request(rpc::RequestKind::Leave);
+            let message = rpc::RPCMessage::new(self.id.clone(), message);
+            for node in neighbours {
+                if self.send_message(node.id().clone(),
+                                     node.address().clone(),
+                                     message.clone()).is_ok() {
+                    report.notified += 1;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Tries to find a key in the map.
+    ///
+    /// Returns an error in the case of an error receiving a message, otherwise
+    /// returns the value if found.
+    pub fn find(&mut self,
+                k: K)
+                -> io::Result<Option<V>> {
+        Ok(self.find_record(k)?.and_then(|r| r.value().cloned()))
+    }
+
+    /// Tries to find the record stored at a key.
+    ///
+    /// Records that don't verify for the key are ignored.
+    ///
+    /// The lookup is split in as many disjoint paths as configured with
+    /// `set_disjoint_paths`.
+    pub fn find_record(&mut self,
+                       k: K)
+                       -> io::Result<Option<Record<V>>> {
+        self.find_record_matching(k, |_| true)
+    }
+
+    /// Tries to find the content-addressed value stored at a key.
+    ///
+    /// Unlike `find`, this refuses anything that isn't a content-addressed
+    /// record whose hash matches the key. Nodes returning a content-addressed
+    /// record with the wrong hash are penalised, but not the ones that hold
+    /// some other kind of record under the key.
+    pub fn find_immutable(&mut self,
+                          k: K)
+                          -> io::Result<Option<V>> {
+        let record = self.find_record_matching(k, |r| match *r {
+            Record::Immutable(..) => true,
+            _ => false,
+        })?;
+        Ok(record.and_then(|r| r.value().cloned()))
+    }
+
+    /// Finds many content-addressed values at once, returning them in the
+    /// same order as `keys`.
+    ///
+    /// A `FIND_VALUE` request for each key is sent right away to the closest
+    /// node we know to it, and the responses are collected in parallel. Only
+    /// the keys that node doesn't answer for go through a full lookup, one by
+    /// one.
+    pub fn find_immutables(&mut self,
+                           keys: &[K])
+                           -> io::Result<Vec<Option<V>>> {
+        let mut found = keys.iter().map(|k| match self.store.peek(k) {
+            Some(&Record::Immutable(ref v)) => Some(v.clone()),
+            _ => None,
+        }).collect::<Vec<_>>();
+
+        let mut pending: HashMap<K, Vec<usize>> = HashMap::new();
+        for (i, k) in keys.iter().enumerate() {
+            if found[i].is_none() {
+                pending.entry(k.clone()).or_insert_with(Vec::new).push(i);
+            }
+        }
+
+        let mut requested = HashMap::new();
+        for k in pending.keys() {
+            let target = k.to_id();
+            let closest = self.find_k_known_nodes_closer_to(&target)
+                .into_iter()
+                .min_by_key(|e| target.xor(e.id()));
+            let node = match closest {
+                Some(node) => node,
+                None => continue,
+            };
+            let message = rpc::RequestKind::FindValue(k.clone(), None);
+            let message = rpc::MessageKind::Request(message);
+            let message = rpc::RPCMessage::new(self.id.clone(), message);
+            if self.send_message(node.id().clone(),
+                                 node.address().clone(),
+                                 message).is_ok() {
+                requested.insert(k.clone(), node.id().clone());
+            }
+        }
+
+        let old_timeout = self.socket.read_timeout()?;
+        let timeout = Duration::from_millis(LOOKUP_RESPONSE_TIMEOUT_MS);
+        self.socket.set_read_timeout(Some(timeout))?;
+        while !requested.is_empty() {
+            let (source, message) = match self.recv_message() {
+                Ok(message) => message,
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => {
+                    self.socket.set_read_timeout(old_timeout)?;
+                    return Err(e);
+                }
+            };
+
+            let rpc::RPCMessage { kind, sender, .. } = message;
+            let response = match kind {
+                rpc::MessageKind::Request(r) => {
+                    let _ = self.handle_request(r, sender, source);
+                    continue;
+                }
+                rpc::MessageKind::Response(rpc::ResponseKind::FindValue(r, _)) => r,
+                _ => continue,
+            };
+
+            let key = match response {
+                rpc::FindValueResponse::Value(key, record) => {
+                    if requested.get(&key) != Some(&sender) {
+                        continue;
+                    }
+                    if let Record::Immutable(ref v) = record {
+                        if record.verify(&key) && self.is_valid(&key, &record) {
+                            for &i in &pending[&key] {
+                                found[i] = Some(v.clone());
+                            }
+                        }
+                    }
+                    key
+                }
+                rpc::FindValueResponse::VerifyAddress(key) => {
+                    // Leave it to the lookup in `find_immutable` to try again.
+                    if requested.get(&key) != Some(&sender) {
+                        continue;
+                    }
+                    key
+                }
+                rpc::FindValueResponse::CloserNodes(key, ..) => {
+                    // Leave it to the lookup in `find_immutable` to follow
+                    // them.
+                    if requested.get(&key) != Some(&sender) {
+                        continue;
+                    }
+                    key
+                }
+            };
+            requested.remove(&key);
+        }
+        self.socket.set_read_timeout(old_timeout)?;
+
+        for (k, indices) in pending {
+            if found[indices[0]].is_some() {
+                continue;
+            }
+            let value = self.find_immutable(k)?;
+            for i in indices {
+                found[i] = value.clone();
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Finds the announcements stored at a key, merging the ones returned by
+    /// the `k` closest nodes that have some.
+    pub fn find_announcements(&mut self,
+                              k: K)
+                              -> io::Result<Vec<Announcement<V>>> {
+        fn is_announcements<V>(r: &Record<V>) -> bool {
+            match *r {
+                Record::Announcements(..) => true,
+                _ => false,
+            }
+        }
+
+        let mut announcements = vec![];
+        let mut records = self.lookup(&k, is_announcements, K)?.found
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect::<Vec<_>>();
+        if let Some(r) = self.store.get(&k) {
+            records.push(r.clone());
+        }
+
+        for record in records {
+            if let Record::Announcements(a) = record {
+                record::merge_announcements(&mut announcements, a);
+            }
+        }
+
+        Ok(announcements)
+    }
+
+    /// Finds the versions of a value stored at a key, merging the ones
+    /// returned by the `k` closest nodes that have some.
+    ///
+    /// Outdated versions are discarded, and the concurrent ones are all
+    /// returned, so that the application can reconcile them and store the
+    /// result with `put_versioned`.
+    pub fn find_versions(&mut self,
+                         k: K)
+                         -> io::Result<Vec<Versioned<V>>> {
+        fn is_versioned<V>(r: &Record<V>) -> bool {
+            match *r {
+                Record::Versioned(..) => true,
+                _ => false,
+            }
+        }
+
+        let mut siblings = vec![];
+        let mut records = self.lookup(&k, is_versioned, K)?.found
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect::<Vec<_>>();
+        if let Some(r) = self.store.get(&k) {
+            records.push(r.clone());
+        }
+
+        for record in records {
+            if let Record::Versioned(versions) = record {
+                version::merge_siblings(&mut siblings, versions);
+            }
+        }
+
+        Ok(siblings)
+    }
+
+    /// Looks up a key until `quorum` records are found, or the lookup is
+    /// exhausted, and returns the distinct records found along with the nodes
+    /// that served each of them.
+    ///
+    /// More than one record means that replicas disagree, either because some
+    /// of them are outdated or because some node is lying. Our own copy, if
+    /// any, counts towards the quorum.
+    pub fn find_quorum(&mut self,
+                       k: K,
+                       quorum: usize)
+                       -> io::Result<Vec<Replica<V>>> {
+        let mut found = vec![];
+        if let Some(r) = self.store.get(&k) {
+            let mut r = r.clone();
+            r.remove_expired();
+            let us = KBucketEntry::new(self.id.clone(),
+                                       self.address()?,
+                                       self.puzzle_solution.clone());
+            found.push((us, r));
+        }
+
+        if found.len() < quorum {
+            let remaining = quorum - found.len();
+            found.extend(self.lookup(&k, |_| true, remaining)?.found);
+        }
+
+        let mut replicas: Vec<Replica<V>> = vec![];
+        for (node, record) in found {
+            match replicas.iter().position(|r| r.record == record) {
+                Some(i) => replicas[i].nodes.push(node),
+                None => replicas.push(Replica {
+                    record: record,
+                    nodes: vec![node],
+                }),
+            }
+        }
+
+        Ok(replicas)
+    }
+
+    /// Does a quorum read like `find_quorum`, and lets `resolve` choose a
+    /// winner among the distinct records found, returning its index.
+    ///
+    /// The nodes that served any other record are then sent the winning one,
+    /// so that outdated replicas get repaired. An index out of bounds is
+    /// returned as an `InvalidInput` error, without repairing anything.
+    pub fn find_resolved<F>(&mut self,
+                            k: K,
+                            quorum: usize,
+                            resolve: F)
+                            -> io::Result<Option<Record<V>>>
+        where F: FnOnce(&K, &[Replica<V>]) -> usize,
+    {
+        let replicas = self.find_quorum(k.clone(), quorum)?;
+        let winner = match replicas.len() {
+            0 => return Ok(None),
+            1 => 0,
+            _ => resolve(&k, &replicas),
+        };
+        if winner >= replicas.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Resolver picked replica {} out of {}",
+                                              winner, replicas.len())));
+        }
+
+        let record = replicas[winner].record.clone();
+        for (i, replica) in replicas.into_iter().enumerate() {
+            if i != winner {
+                self.repair(&k, &record, replica.nodes);
+            }
+        }
+
+        Ok(Some(record))
+    }
+
+    /// Does a quorum read like `find_quorum` for a plain mergeable value, see
+    /// the `crdt` module, and merges the values of every replica.
+    ///
+    /// The merged value is sent back to the replicas that served anything
+    /// else, so that they converge. If the values aren't mergeable, the last
+    /// one found is returned, and no replica is repaired.
+    pub fn find_merged(&mut self,
+                       k: K,
+                       quorum: usize)
+                       -> io::Result<Option<V>> {
+        let mut merged: Option<V> = None;
+        let mut mergeable = true;
+        let mut outdated = vec![];
+        for replica in self.find_quorum(k.clone(), quorum)? {
+            let value = match replica.record {
+                Record::Plain(v) => v,
+                _ => continue,
+            };
+
+            merged = Some(match merged {
+                Some(mut merged) => {
+                    if !merged.merge_from(&value) {
+                        mergeable = false;
+                        merged = value.clone();
+                    }
+                    merged
+                }
+                None => value.clone(),
+            });
+            outdated.push((value, replica.nodes));
+        }
+
+        let merged = match merged {
+            Some(merged) => merged,
+            None => return Ok(None),
+        };
+        if !mergeable {
+            return Ok(Some(merged));
+        }
+
+        let record = Record::Plain(merged.clone());
+        for (value, nodes) in outdated {
+            if value != merged {
+                self.repair(&k, &record, nodes);
+            }
+        }
+
+        Ok(Some(merged))
+    }
+
+    /// Sends `record` to the given replicas of `k`, which hold an outdated
+    /// copy of it, repairing our own copy directly.
+    fn repair(&mut self, k: &K, record: &Record<V>, nodes: Vec<KBucketEntry>) {
+        for node in nodes {
+            debug!("[{}] Repairing {:?} at {}", self.id, k, node.id());
+            if *node.id() == self.id {
+                if let Err(err) =
+                    self.store_record(k.clone(), record.clone(), None, None, None) {
+                    debug!("[{}] Failed to repair our copy: {:?}", self.id, err);
+                }
+                continue;
+            }
+
+            let message = self.store_request(node.id(), k.clone(), record.clone(), None);
+            let message = rpc::MessageKind::Request(message);
+            let message = rpc::RPCMessage::new(self.id.clone(), message);
+            let _ = self.send_message(node.id().clone(),
+                                      node.address().clone(),
+                                      message);
+        }
+    }
+
+    /// Tries to find a record at a key that verifies and satisfies
+    /// `is_acceptable`.
+    fn find_record_matching<F>(&mut self,
+                               k: K,
+                               is_acceptable: F)
+                               -> io::Result<Option<Record<V>>>
+        where F: Fn(&Record<V>) -> bool,
+    {
+        trace!("[{}] Looking at {:?}", self.id(), k);
+
+        if let Some(r) = self.store.get(&k) {
+            if is_acceptable(r) {
+                return Ok(Some(r.clone()));
+            }
+        }
+
+        let target = k.to_id();
+        let outcome = self.lookup(&k, is_acceptable, 1)?;
+        let record = match outcome.found.into_iter().next() {
+            Some((_, record)) => record,
+            None => return Ok(None),
+        };
+
+        // Cache the record in the closest node that didn't have it, so that
+        // popular keys don't overload the nodes closest to them.
+        let closest_missed =
+            outcome.missed.into_iter().min_by_key(|e| target.xor(e.id()));
+        if let Some(node) = closest_missed {
+            trace!("[{}] Caching {:?} at {}", self.id, k, node.id());
+            let stamp = puzzle::solve_stamp(&self.id,
+                                            node.id(),
+                                            &k,
+                                            &record,
+                                            &self.stamp_difficulty);
+            let message = rpc::RequestKind::Cache(k, record.clone(), stamp, None);
+            let message = rpc::MessageKind::Request(message);
+            let message = rpc::RPCMessage::new(self.id.clone(), message);
+            let _ = self.send_message(node.id().clone(),
+                                      node.address().clone(),
+                                      message);
+        }
+
+        Ok(Some(record))
+    }
+
+    /// Looks up a key in the network, until `max_values` records that verify
+    /// and satisfy `is_acceptable` are found, or the lookup is exhausted.
+    fn lookup<F>(&mut self,
+                 k: &K,
+                 is_acceptable: F,
+                 max_values: usize)
+                 -> io::Result<LookupOutcome<V>>
+        where F: Fn(&Record<V>) -> bool,
+    {
+        let old_timeout = self.socket.read_timeout()?;
+        let result = self.find_remote(k, is_acceptable, max_values);
+        self.socket.set_read_timeout(old_timeout)?;
+        result
+    }
+
+    /// Does the network part of `find`, following the disjoint lookup paths
+    /// described in the S/Kademlia paper.
+    ///
+    /// The initial candidates are distributed across the paths, and each path
+    /// only follows the contacts returned by the nodes it queried. A node is
+    /// never queried by more than one path, so a single malicious node can
+    /// only steer the path it belongs to.
+    ///
+    /// Each node queried is given `LOOKUP_RESPONSE_TIMEOUT_MS` to answer, and
+    /// changes the socket read timeout to wait no longer than that.
+    ///
+    /// Records that don't verify or aren't acceptable are skipped, and the
+    /// nodes returning content-addressed records that don't match their key
+    /// are penalised.
+    fn find_remote<F>(&mut self,
+                      k: &K,
+                      is_acceptable: F,
+                      max_values: usize)
+                      -> io::Result<LookupOutcome<V>>
+        where F: Fn(&Record<V>) -> bool,
+    {
+        let mut outcome = LookupOutcome {
+            found: vec![],
+            missed: vec![],
+        };
+        let request =
+            rpc::MessageKind::Request(rpc::RequestKind::FindValue(k.clone(), None));
+        let request =
+            rpc::RPCMessage::new(self.id.clone(), request);
+
+        let mut paths: Vec<LookupPath> =
+            (0..self.disjoint_paths).map(|_| LookupPath::new()).collect();
+
+        // All the nodes that have been queried by some path, to guarantee that
+        // paths stay disjoint. We never want to query ourselves either.
+        let mut queried = HashSet::new();
+        queried.insert(self.id.clone());
+
+        let target = k.to_id();
+        let initial = self.find_k_known_nodes_closer_to(&target);
+        for (i, node) in initial.into_iter().enumerate() {
+            let path_count = paths.len();
+            paths[i % path_count].candidates.push(node);
+        }
+
+        // The path each of the nodes we're waiting a response from belongs to,
+        // and when we give up on it.
+        let timeout = Duration::from_millis(LOOKUP_RESPONSE_TIMEOUT_MS);
+        let mut pending = HashMap::new();
+        // The nodes that asked us to verify our address, or left out some of
+        // the closer nodes until we do, which we only ask again once.
+        let mut verifying = HashSet::new();
+        loop {
+            for (index, path) in paths.iter_mut().enumerate() {
+                trace!("[{}] path {}: candidates: {:?}", self.id, index,
+                       path.candidates);
+                for node in path.candidates.drain(..) {
+                    // Another path may have got to it first.
+                    if !queried.insert(node.id().clone()) {
+                        continue;
+                    }
+                    let deadline = Instant::now() + timeout;
+                    pending.insert(node.id().clone(), (index, node.clone(), deadline));
+                    let _ = self.send_message(node.id().clone(),
+                                              node.address().clone(),
+                                              request.clone());
+                }
+            }
+
+            // Give up on the nodes that didn't answer in time, and wait for
+            // the rest until the first of them is due.
+            let now = Instant::now();
+            pending.retain(|id, &mut (_, _, deadline)| {
+                if deadline <= now {
+                    debug!("Timed out waiting for {}", id);
+                }
+                deadline > now
+            });
+            let next_deadline = match pending.values().map(|p| p.2).min() {
+                Some(deadline) => deadline,
+                None => return Ok(outcome),
+            };
+            self.socket.set_read_timeout(Some(next_deadline - now))?;
+
+            // FIXME(emilio): This blocks, which is suboptimal. A better
+            // approach would be making a generic "observer" interface that
+            // observed new messages and resolved a future with the value if
+            // found...
+            let (source, message) = match self.recv_message() {
+                Ok(m) => m,
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e),
+            };
+
+            match message.kind {
+                rpc::MessageKind::Request(r) => {
+                    let _ = self.handle_request(r, message.sender, source);
+                }
+                rpc::MessageKind::Response(rpc::ResponseKind::FindValue(fvr, _)) => {
+                    let key = match fvr {
+                        rpc::FindValueResponse::Value(ref key, _) |
+                        rpc::FindValueResponse::CloserNodes(ref key, ..) |
+                        rpc::FindValueResponse::VerifyAddress(ref key) => key.clone(),
+                    };
+                    if key != *k {
+                        debug!("Received stale response for key {:?}", key);
+                        continue;
+                    }
+
+                    let (path, entry) = match pending.remove(&message.sender) {
+                        Some((path, entry, _)) => (path, entry),
+                        None => {
+                            debug!("Received unexpected response from {:?}",
+                                   message.sender);
+                            continue;
+                        }
+                    };
+
+                    match fvr {
+                        rpc::FindValueResponse::Value(key, v) => {
+                            trace!("Got Value({:?}, {:?})", key, v);
+                            if !v.verify(k) {
+                                debug!("Received invalid record from {:?}",
+                                       message.sender);
+                                // Only a hash mismatch proves tampering, since
+                                // the content is all that's needed to check it.
+                                if let Record::Immutable(..) = v {
+                                    self.penalise(&message.sender);
+                                }
+                            } else if !is_acceptable(&v) {
+                                // The node may legitimately hold some other
+                                // kind of record under the key.
+                                debug!("Received unacceptable record from {:?}",
+                                       message.sender);
+                            } else if !self.is_valid(k, &v) {
+                                // Other nodes may not enforce the same rules
+                                // as us, so don't penalise them.
+                                debug!("Received record rejected by our \
+                                        validator from {:?}", message.sender);
+                            } else {
+                                outcome.found.push((entry, v));
+                                if outcome.found.len() >= max_values {
+                                    return Ok(outcome);
+                                }
+                            }
+                        }
+                        rpc::FindValueResponse::VerifyAddress(..) => {
+                            // The response carried a write token, which the
+                            // new request proves our address with.
+                            if verifying.insert(entry.id().clone()) {
+                                let _ = self.send_message(entry.id().clone(),
+                                                          entry.address().clone(),
+                                                          request.clone());
+                                let deadline = Instant::now() + timeout;
+                                pending.insert(entry.id().clone(), (path, entry, deadline));
+                            }
+                        }
+                        rpc::FindValueResponse::CloserNodes(_, nodes, truncated) => {
+                            // Like above, but following the nodes we got in
+                            // the meantime.
+                            if truncated && verifying.insert(entry.id().clone()) {
+                                let _ = self.send_message(entry.id().clone(),
+                                                          entry.address().clone(),
+                                                          request.clone());
+                                let deadline = Instant::now() + timeout;
+                                pending.insert(entry.id().clone(),
+                                               (path, entry.clone(), deadline));
+                            } else {
+                                outcome.missed.push(entry);
+                            }
+                            // Nodes dropped here aren't marked as queried, so
+                            // they can still be followed if they show up again.
+                            let candidates = &mut paths[path].candidates;
+                            for node in nodes {
+                                if !puzzle::verify(node.id(),
+                                                   node.puzzle_solution(),
+                                                   &self.puzzle_difficulty) {
+                                    continue;
+                                }
+                                if queried.contains(node.id()) ||
+                                   candidates.iter().any(|c| c.id() == node.id()) {
+                                    continue;
+                                }
+                                candidates.push(node);
+                            }
+                            candidates.sort_by_key(|e| target.xor(e.id()));
+                            candidates.truncate(K);
+                        }
+                    }
+                }
+                rpc::MessageKind::Response(..) => {
+                    // FIXME(emilio): With the non-blocking, observer-based
                     // interface, this would probably be way nicer.
                     debug!("Received stale response {:?}", message);
                 }
@@ -458,3 +2140,815 @@ impl Node {
         }
     }
 }
+
+/// Records whose key is derived from their contents can only be stored with
+/// `NodeId` keys.
+impl<V> Node<NodeId, V>
+    where V: Value,
+{
+    /// Sends a store message for a mutable record, which is stored at the key
+    /// derived from its public key and salt.
+    ///
+    /// If `cas` is specified, the nodes will only accept the record if the one
+    /// they have has that sequence number.
+    ///
+    /// Returns an error if the record can't replace the one we have locally.
+    pub fn try_store_mutable(&mut self,
+                             record: MutableRecord<V>,
+                             cas: Option<u64>)
+                             -> Result<(), storage::StoreError> {
+        let key = record.key();
+        self.try_store_record(key, Record::Mutable(record), cas)
+    }
+
+    /// Sends a store message for a content-addressed value, which is stored at
+    /// its hash.
+    ///
+    /// Returns the key of the value.
+    pub fn try_store_immutable(&mut self,
+                               value: V)
+                               -> Result<NodeId, storage::StoreError> {
+        let key = storage::hash_value(&value);
+        self.try_store_record(key.clone(), Record::Immutable(value), None)?;
+        Ok(key)
+    }
+}
+
+impl Node<NodeId, Vec<u8>> {
+    /// Creates a new node with a freshly generated identity, or returns an
+    /// error if the function couldn't open the OS rng, or couldn't open the
+    /// appropriate port.
+    pub fn new<A>(addr: A) -> Result<Self, io::Error>
+        where A: ToSocketAddrs,
+    {
+        Self::bind(addr)
+    }
+
+    /// Creates a new node with a given identity, or returns an error if the
+    /// function couldn't open the OS rng, or couldn't open the appropriate
+    /// port.
+    pub fn with_keypair<A>(addr: A, keypair: Keypair) -> Result<Self, io::Error>
+        where A: ToSocketAddrs,
+    {
+        Self::bind_with_keypair(addr, keypair)
+    }
+
+    /// Encrypts a private value named `name` with a key derived from `secret`,
+    /// see the `encryption` module, and stores it as a mutable record signed
+    /// with a keypair derived from them too.
+    ///
+    /// The sequence number of the record is the current time in milliseconds,
+    /// so later values replace earlier ones. Returns the key the value is
+    /// stored under.
+    pub fn try_store_private(&mut self,
+                             secret: &Secret,
+                             name: &[u8],
+                             value: &[u8])
+                             -> Result<NodeId, storage::StoreError> {
+        let keypair = secret.keypair_for(name);
+        let key = secret.key_for(name);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0));
+        let mut seq = now.as_secs().saturating_mul(1000) + now.subsec_nanos() as u64 / 1_000_000;
+        if let Some(&Record::Mutable(ref existing)) = self.store.peek(&key) {
+            seq = ::std::cmp::max(seq, existing.seq.saturating_add(1));
+        }
+
+        let sealed = secret.encrypt(&mut self.rng, name, value);
+        let record = MutableRecord::new(&keypair, vec![], seq, sealed);
+        self.try_store_record(key.clone(), Record::Mutable(record), None)?;
+        Ok(key)
+    }
+
+    /// Finds a private value stored with `try_store_private`, and decrypts it.
+    ///
+    /// Only the holders of `secret` can sign the record, so a value that
+    /// doesn't decrypt is returned as `None` rather than blamed on the node
+    /// serving it.
+    pub fn find_private(&mut self,
+                        secret: &Secret,
+                        name: &[u8])
+                        -> io::Result<Option<Vec<u8>>> {
+        let key = secret.key_for(name);
+        let record = self.find_record_matching(key, |r| match *r {
+            Record::Mutable(..) => true,
+            _ => false,
+        })?;
+
+        Ok(match record {
+            Some(Record::Mutable(record)) => secret.decrypt(name, &record.value),
+            _ => None,
+        })
+    }
+
+    /// Erasure-codes a blob into `data_shards` data shards and `parity_shards`
+    /// parity shards, see the `erasure` module, and stores them, along with the
+    /// manifest of the blob.
+    ///
+    /// Each shard is sent only to the closest node we know to its key, or
+    /// stored locally if we don't know any, so the blob survives as long as
+    /// the nodes holding `data_shards` of them do. The manifest is stored like
+    /// any other content-addressed value.
+    ///
+    /// Returns the key of the manifest, which identifies the blob, or an error
+    /// if `data_shards` is zero, or if there are more than
+    /// `erasure::MAX_SHARDS` shards.
+    pub fn try_store_erasure_coded(&mut self,
+                                   blob: &[u8],
+                                   data_shards: usize,
+                                   parity_shards: usize)
+                                   -> Result<NodeId, erasure::ErasureError> {
+        let (manifest, shards) = erasure::encode(blob, data_shards, parity_shards)?;
+        for (key, shard) in manifest.shards.iter().zip(shards) {
+            let closest = self.find_k_known_nodes_closer_to(key)
+                .into_iter()
+                .min_by_key(|e| key.xor(e.id()));
+            let record = Record::Immutable(shard);
+            let node = match closest {
+                Some(node) => node,
+                None => {
+                    self.store_record(key.clone(), record, None, None, None)?;
+                    continue;
+                }
+            };
+
+            if let Err(err) = self.fetch_write_tokens(key, &[node.clone()]) {
+                debug!("[{}] Failed to fetch write tokens: {:?}", self.id, err);
+            }
+            let message = self.store_request(node.id(), key.clone(), record, None);
+            let message = rpc::MessageKind::Request(message);
+            let message = rpc::RPCMessage::new(self.id.clone(), message);
+            if let Err(err) = self.send_message(node.id().clone(),
+                                                node.address().clone(),
+                                                message) {
+                error!("Failed to send shard to {:?}, {:?}", node.id(), err);
+            }
+        }
+
+        Ok(self.try_store_immutable(manifest.to_bytes())?)
+    }
+
+    /// Finds an erasure-coded blob stored with `try_store_erasure_coded`,
+    /// given the key of its manifest.
+    ///
+    /// Shards are looked up until there are enough of them to get each stripe
+    /// back. Returns `None` if the manifest or too many shards are missing.
+    pub fn find_erasure_coded(&mut self,
+                              key: NodeId)
+                              -> io::Result<Option<Vec<u8>>> {
+        let manifest = match self.find_immutable(key)? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+        let manifest = match erasure::Manifest::from_bytes(&manifest) {
+            Some(manifest) => manifest,
+            None => return Ok(None),
+        };
+
+        let mut shards = vec![None; manifest.shards.len()];
+        let mut found = 0;
+        for (i, shard_key) in manifest.shards.iter().enumerate() {
+            if i % manifest.stripe_len() == 0 {
+                found = 0;
+            }
+            if found == manifest.data_shards {
+                continue;
+            }
+            if let Some(shard) = self.find_immutable(shard_key.clone())? {
+                shards[i] = Some(shard);
+                found += 1;
+            }
+        }
+
+        Ok(erasure::decode(&manifest, shards))
+    }
+}
+
+/// Creates a node on a local address, which knows the `known` nodes and
+/// doesn't hand records off on its own.
+#[cfg(test)]
+fn local_node<K: Key, V: Value>(known: &[&Node<K, V>]) -> Node<K, V> {
+    let mut node = Node::bind("127.0.0.1:0").unwrap();
+    node.set_handoff_rate(0);
+    for other in known {
+        node.note_node(other.id(), &other.address().unwrap(), other.puzzle_solution());
+    }
+    node
+}
+
+/// Serves the requests `node` gets in a new thread, until it doesn't get any
+/// for a second, and returns it back.
+#[cfg(test)]
+fn serve<K, V>(mut node: Node<K, V>) -> ::std::thread::JoinHandle<Node<K, V>>
+    where K: Key + Send + 'static,
+          V: Value + Send + 'static,
+{
+    ::std::thread::spawn(move || {
+        node.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        loop {
+            match node.recv_message() {
+                Ok((source, message)) => {
+                    if let rpc::MessageKind::Request(r) = message.kind {
+                        let _ = node.handle_request(r, message.sender, source);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => continue,
+                Err(..) => return node,
+            }
+        }
+    })
+}
+
+#[test]
+fn disjoint_lookups_query_each_node_once() {
+    // Few enough nodes that every response to an unverified address fits,
+    // counting the client once the servers know it.
+    let mut servers: Vec<Node> = (0..4).map(|_| local_node(&[])).collect();
+    let contacts = servers.iter()
+        .map(|n| (n.id().clone(), n.address().unwrap(), n.puzzle_solution().clone()))
+        .collect::<Vec<_>>();
+    for server in &mut servers {
+        for &(ref id, ref address, ref solution) in &contacts {
+            if id != server.id() {
+                server.note_node(id, address, solution);
+            }
+        }
+    }
+
+    // The holder is the closest node to the key, so every node knowing it
+    // sends it along.
+    let key = storage::hash(b"key");
+    servers.sort_by_key(|n| key.xor(n.id()));
+    servers[0].store_record(key.clone(), Record::Plain(b"value".to_vec()), None, None, None)
+        .unwrap();
+
+    let mut client = local_node(&servers[1..4].iter().collect::<Vec<_>>());
+    client.set_disjoint_paths(3);
+
+    let servers = servers.into_iter().map(serve).collect::<Vec<_>>();
+    assert_eq!(client.find(key).unwrap(), Some(b"value".to_vec()));
+
+    for server in servers {
+        let server = server.join().unwrap();
+        assert!(server.rate_limiter().stats(RequestClass::FindValue).allowed <= 1);
+    }
+}
+
+#[test]
+fn lookups_only_give_up_on_silent_nodes() {
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let silent_id = NodeId::random(&mut rand::OsRng::new().unwrap());
+
+    let mut holder = local_node(&[]);
+    let key = storage::hash(b"key");
+    holder.store_record(key.clone(), Record::Plain(b"value".to_vec()), None, None, None)
+        .unwrap();
+
+    let mut client = local_node(&[]);
+    client.set_disjoint_paths(2);
+    client.note_node(&silent_id, &silent.local_addr().unwrap(), &NodeId::from_bytes([0; 20]));
+    client.note_node(holder.id(), &holder.address().unwrap(), holder.puzzle_solution());
+
+    let holder = serve(holder);
+    let start = Instant::now();
+    assert_eq!(client.find(key.clone()).unwrap(), Some(b"value".to_vec()));
+    assert!(start.elapsed() < Duration::from_millis(LOOKUP_RESPONSE_TIMEOUT_MS));
+
+    // A lookup for a missing key waits for the silent node, but not forever.
+    let start = Instant::now();
+    assert_eq!(client.find(storage::hash(b"missing")).unwrap(), None);
+    assert!(start.elapsed() >= Duration::from_millis(LOOKUP_RESPONSE_TIMEOUT_MS));
+    holder.join().unwrap();
+}
+
+#[test]
+fn immutable_lookups_penalise_tampering_only() {
+    let value = b"value".to_vec();
+    let key = storage::hash_value(&value);
+
+    // A node holding a plain record under the key, which is fine, and one
+    // serving a content-addressed record that doesn't match it.
+    let mut other = local_node(&[]);
+    other.store_record(key.clone(), Record::Plain(b"other".to_vec()), None, None, None)
+        .unwrap();
+    let mut liar = local_node(&[]);
+    let tampered = Record::Immutable(b"tampered".to_vec());
+    assert_eq!(tampered.check_update(&key, None, None),
+               Err(storage::StoreError::HashMismatch));
+    liar.store.insert(key.clone(), tampered, storage::Source::Local, None).unwrap();
+
+    let mut client = local_node(&[&other, &liar]);
+    client.set_disjoint_paths(2);
+
+    let (other_id, liar_id) = (other.id().clone(), liar.id().clone());
+    let servers = vec![serve(other), serve(liar)];
+    assert_eq!(client.find_immutable(key.clone()).unwrap(), None);
+    assert!(!client.is_penalised(&other_id));
+    assert!(client.is_penalised(&liar_id));
+
+    let mut honest = local_node(&[]);
+    honest.store_record(key.clone(), Record::Immutable(value.clone()), None, None, None)
+        .unwrap();
+    client.note_node(honest.id(), &honest.address().unwrap(), honest.puzzle_solution());
+    let honest = serve(honest);
+    assert_eq!(client.find_immutable(key).unwrap(), Some(value));
+
+    honest.join().unwrap();
+    for server in servers {
+        server.join().unwrap();
+    }
+}
+
+#[test]
+fn typed_nodes_store_and_find_typed_values() {
+    let key = storage::NamespacedKey::new("chat", "profile", b"alice");
+
+    let mut holder = local_node::<storage::NamespacedKey, String>(&[]);
+    holder.store_record(key.clone(), Record::Plain("Alice".to_string()), None, None, None)
+        .unwrap();
+
+    let mut client = local_node(&[&holder]);
+
+    let holder = serve(holder);
+    assert_eq!(client.find(key.clone()).unwrap(), Some("Alice".to_string()));
+    let other = storage::NamespacedKey::new("chat", "avatar", b"alice");
+    assert_eq!(client.find(other).unwrap(), None);
+    holder.join().unwrap();
+}
+
+/// A validator refusing empty values, and preferring the longest value.
+#[cfg(test)]
+struct LongestValue;
+
+#[cfg(test)]
+impl RecordValidator for LongestValue {
+    fn validate(&self, _key: &NodeId, record: &Record) -> Result<(), String> {
+        match record.value() {
+            Some(value) if value.is_empty() => Err("empty value".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    fn select(&self, _key: &NodeId, records: &[Record]) -> usize {
+        let len = |r: &Record| r.value().map_or(0, |v| v.len());
+        let longest = records.iter().map(&len).max().unwrap_or(0);
+        records.iter().position(|r| len(r) == longest).unwrap_or(0)
+    }
+}
+
+#[test]
+fn validators_reject_and_select_records() {
+    let mut node = local_node(&[]);
+    node.set_validator(Box::new(LongestValue));
+
+    let key = storage::hash(b"key");
+    assert_eq!(node.store_record(key.clone(), Record::Plain(vec![]), None, None, None),
+               Err(storage::StoreError::Rejected("empty value".to_string())));
+    node.store_record(key.clone(), Record::Plain(b"long".to_vec()), None, None, None)
+        .unwrap();
+    assert_eq!(node.store_record(key.clone(), Record::Plain(b"s".to_vec()), None, None, None),
+               Err(storage::StoreError::NotSelected));
+    node.store_record(key.clone(), Record::Plain(b"longer".to_vec()), None, None, None)
+        .unwrap();
+    assert_eq!(node.store.peek(&key), Some(&Record::Plain(b"longer".to_vec())));
+
+    // Records the validator refuses are ignored in lookups too.
+    let mut holder = local_node(&[]);
+    let empty = storage::hash(b"empty");
+    holder.store_record(empty.clone(), Record::Plain(vec![]), None, None, None).unwrap();
+    node.note_node(holder.id(), &holder.address().unwrap(), holder.puzzle_solution());
+    let holder = serve(holder);
+    assert_eq!(node.find(empty).unwrap(), None);
+    holder.join().unwrap();
+}
+
+#[test]
+fn handoffs_are_queued_once_per_key() {
+    let mut node = Node::new("127.0.0.1:0").unwrap();
+    node.set_handoff_rate(1);
+    for key in &[b"one", b"two", b"six"] {
+        node.store_record(storage::hash(*key), Record::Plain(key.to_vec()), None, None, None)
+            .unwrap();
+    }
+
+    let others = (0..2)
+        .map(|_| Node::new("127.0.0.1:0").unwrap())
+        .collect::<Vec<_>>();
+    for _ in 0..2 {
+        for other in &others {
+            node.note_node(other.id(), &other.address().unwrap(), other.puzzle_solution());
+        }
+    }
+
+    // The first node got one record right away, and each of the rest is
+    // queued once for one node or both.
+    assert_eq!(node.pending_handoffs.len(), 3);
+    let targets = node.handoff_targets.values().map(|t| t.len()).sum::<usize>();
+    assert_eq!(targets, 2 * 3 - 1);
+
+    node.set_handoff_rate(0);
+    assert!(node.pending_handoffs.is_empty());
+    assert!(node.handoff_targets.is_empty());
+}
+
+#[test]
+fn resolvers_picking_missing_replicas_fail() {
+    let key = storage::hash(b"key");
+    let mut holder = local_node(&[]);
+    holder.store_record(key.clone(), Record::Plain(b"theirs".to_vec()), None, None, None)
+        .unwrap();
+
+    let mut node = local_node(&[&holder]);
+    node.store_record(key.clone(), Record::Plain(b"ours".to_vec()), None, None, None)
+        .unwrap();
+
+    let holder = serve(holder);
+    let err = node.find_resolved(key.clone(), 2, |_, replicas| replicas.len()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let resolved = node.find_resolved(key, 2, |_, replicas| {
+        replicas.iter().position(|r| r.record == Record::Plain(b"ours".to_vec())).unwrap()
+    });
+    assert_eq!(resolved.unwrap(), Some(Record::Plain(b"ours".to_vec())));
+    let holder = holder.join().unwrap();
+    assert_eq!(holder.store.peek(&storage::hash(b"key")),
+               Some(&Record::Plain(b"ours".to_vec())));
+}
+
+#[test]
+fn only_merged_values_are_repaired() {
+    use crdt::GSet;
+
+    let key = storage::hash(b"key");
+    let mut holder = local_node::<NodeId, GSet<u8>>(&[]);
+    let mut theirs = GSet::new();
+    theirs.insert(1);
+    holder.store_record(key.clone(), Record::Plain(theirs), None, None, None).unwrap();
+
+    let mut node = local_node(&[&holder]);
+    let mut ours = GSet::new();
+    ours.insert(2);
+    node.store_record(key.clone(), Record::Plain(ours), None, None, None).unwrap();
+
+    let holder = serve(holder);
+    let merged = node.find_merged(key.clone(), 2).unwrap().unwrap();
+    assert_eq!(merged.iter().cloned().collect::<Vec<_>>(), vec![1, 2]);
+    let holder = holder.join().unwrap();
+    assert_eq!(holder.store.peek(&key), Some(&Record::Plain(merged)));
+
+    // Values that don't merge are left alone.
+    let mut holder = local_node(&[]);
+    holder.store_record(key.clone(), Record::Plain(b"theirs".to_vec()), None, None, None)
+        .unwrap();
+    let mut node = local_node(&[&holder]);
+    node.store_record(key.clone(), Record::Plain(b"ours".to_vec()), None, None, None)
+        .unwrap();
+
+    let holder = serve(holder);
+    assert!(node.find_merged(key.clone(), 2).unwrap().is_some());
+    let holder = holder.join().unwrap();
+    assert_eq!(holder.store.peek(&key), Some(&Record::Plain(b"theirs".to_vec())));
+}
+
+#[test]
+fn tombstones_are_kept_for_a_bounded_time() {
+    let mut node: Node = local_node(&[]);
+    node.set_max_tombstone_ttl(Duration::from_secs(60));
+
+    let keypair = Keypair::generate(&mut rand::OsRng::new().unwrap());
+    let key = MutableRecord::<Vec<u8>>::key_for(&keypair.public_key(), b"salt");
+    let forever = Duration::from_secs(u64::max_value());
+    let tombstone = Tombstone::new(&keypair, &key, b"salt".to_vec(), 1, forever);
+    assert_eq!(tombstone.expires_at, u64::max_value());
+    node.store_record(key.clone(), Record::Tombstone(tombstone), None, None, None).unwrap();
+
+    let expires_at = node.store.entry(&key).unwrap().expires_at.unwrap();
+    assert!(expires_at <= Instant::now() + Duration::from_secs(60));
+}
+
+#[test]
+fn batched_lookups_match_responses_to_keys() {
+    let found = b"found".to_vec();
+    let missing = b"missing".to_vec();
+    let mut holder = local_node(&[]);
+    holder.store_record(storage::hash_value(&found), Record::Immutable(found.clone()),
+                        None, None, None)
+        .unwrap();
+
+    let mut client = local_node(&[&holder]);
+
+    let holder = serve(holder);
+    let keys = [storage::hash_value(&missing), storage::hash_value(&found)];
+    assert_eq!(client.find_immutables(&keys).unwrap(), vec![None, Some(found)]);
+
+    // Only the missing key needed a full lookup.
+    let holder = holder.join().unwrap();
+    assert_eq!(holder.rate_limiter().stats(RequestClass::FindValue).allowed, 3);
+}
+
+#[test]
+fn concurrent_writers_leave_bounded_siblings() {
+    let key = storage::hash(b"key");
+    let mut holder = local_node(&[]);
+
+    // Nobody can pile up siblings without reconciling them.
+    let flood = (0..version::MAX_SIBLINGS as u8 + 1)
+        .map(|i| {
+            let mut clock = VectorClock::new();
+            clock.increment(&NodeId::from_bytes([i; 20]));
+            Versioned { clock: clock, value: vec![i] }
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(holder.store_record(key.clone(), Record::Versioned(flood), None, None, None),
+               Err(storage::StoreError::TooManyVersions));
+
+    let mut writers = (0..2).map(|_| local_node(&[&holder])).collect::<Vec<_>>();
+    let mut reader = local_node(&[&holder]);
+    let holder = serve(holder);
+
+    writers[0].put_versioned(key.clone(), b"one".to_vec(), VectorClock::new()).unwrap();
+    writers[1].put_versioned(key.clone(), b"two".to_vec(), VectorClock::new()).unwrap();
+
+    let siblings = reader.find_versions(key.clone()).unwrap();
+    assert_eq!(siblings.len(), 2);
+
+    // Reconciling them leaves a single version.
+    writers[0].put_versioned(key.clone(), b"three".to_vec(), version::context(&siblings))
+        .unwrap();
+    let siblings = reader.find_versions(key.clone()).unwrap();
+    assert_eq!(siblings.iter().map(|s| s.value.clone()).collect::<Vec<_>>(),
+               vec![b"three".to_vec()]);
+    holder.join().unwrap();
+}
+
+#[test]
+fn private_values_can_only_be_replaced_by_secret_holders() {
+    let mut rng = rand::OsRng::new().unwrap();
+    let secret = Secret::generate(&mut rng);
+    let mut holder = local_node(&[]);
+
+    let mut writer = local_node(&[]);
+    let key = writer.try_store_private(&secret, b"db-password", b"hunter2").unwrap();
+    let record = writer.store.peek(&key).unwrap().clone();
+    holder.store_record(key.clone(), record, None, None, None).unwrap();
+    assert_eq!(holder.store_record(key.clone(), Record::Plain(b"junk".to_vec()),
+                                   None, None, None),
+               Err(storage::StoreError::Protected));
+
+    // Newer values replace older ones, even within the same millisecond.
+    writer.try_store_private(&secret, b"db-password", b"hunter3").unwrap();
+    let record = writer.store.peek(&key).unwrap().clone();
+    holder.store_record(key.clone(), record, None, None, None).unwrap();
+
+    let mut reader = local_node(&[&holder]);
+    let holder_id = holder.id().clone();
+    let holder = serve(holder);
+    assert_eq!(reader.find_private(&secret, b"db-password").unwrap(),
+               Some(b"hunter3".to_vec()));
+    assert_eq!(reader.find_private(&Secret::generate(&mut rng), b"db-password").unwrap(),
+               None);
+    assert!(!reader.is_penalised(&holder_id));
+    holder.join().unwrap();
+}
+
+#[test]
+fn cache_requests_need_a_stamp_for_the_receiver() {
+    let mut rng = rand::OsRng::new().unwrap();
+    let difficulty = puzzle::StampDifficulty { base_bits: 8, scale_with_size: false };
+    let mut cache: Node = local_node(&[]);
+    cache.set_stamp_difficulty(difficulty);
+    let mut sender = local_node(&[]);
+
+    let cache_id = cache.id().clone();
+    let address = cache.address().unwrap();
+    let cache = serve(cache);
+
+    let record = Record::Plain(b"junk".to_vec());
+    let unstamped = NodeId::random(&mut rng);
+    let stamped = NodeId::random(&mut rng);
+    let stamps = vec![
+        (unstamped.clone(), puzzle::Stamp::default()),
+        (stamped.clone(),
+         puzzle::solve_stamp(sender.id(), &cache_id, &stamped, &record, &difficulty)),
+    ];
+    for (key, stamp) in stamps {
+        let message = rpc::RequestKind::Cache(key, record.clone(), stamp, None);
+        let message = rpc::MessageKind::Request(message);
+        let message = rpc::RPCMessage::new(sender.id().clone(), message);
+        sender.send_message(cache_id.clone(), address, message).unwrap();
+    }
+
+    let cache = cache.join().unwrap();
+    assert!(cache.store.peek(&unstamped).is_none());
+    assert!(cache.store.peek(&stamped).is_some());
+}
+
+#[test]
+fn cached_copies_expire_sooner_away_from_the_key() {
+    let mut rng = rand::OsRng::new().unwrap();
+    let mut cache: Node = local_node(&[]);
+    let discard = "127.0.0.1:9".parse().unwrap();
+    let known = (0..K).map(|_| NodeId::random(&mut rng)).collect::<Vec<_>>();
+    for id in &known {
+        cache.note_node(id, &discard, &NodeId::random(&mut rng));
+    }
+
+    // No node we know is closer to our own id than us, while the known ones
+    // are closer to theirs.
+    let sender = NodeId::random(&mut rng);
+    let record = Record::Plain(b"value".to_vec());
+    let near = cache.id().clone();
+    let far = known[0].clone();
+    for key in vec![near.clone(), far.clone()] {
+        let stamp = puzzle::solve_stamp(&sender, cache.id(), &key, &record,
+                                        &cache.stamp_difficulty);
+        let request = rpc::RequestKind::Cache(key, record.clone(), stamp, None);
+        cache.handle_request(request, sender.clone(), discard).unwrap();
+    }
+
+    let max_ttl = Duration::from_secs(CACHE_MAX_TTL_SECS);
+    let near_expiry = cache.store.entry(&near).unwrap().expires_at.unwrap();
+    let far_expiry = cache.store.entry(&far).unwrap().expires_at.unwrap();
+    assert!(near_expiry > Instant::now() + max_ttl / 2);
+    assert!(far_expiry <= Instant::now() + max_ttl / 2);
+}
+
+#[test]
+fn handoffs_wait_for_a_write_token() {
+    let key = storage::hash(b"key");
+    let mut node = Node::new("127.0.0.1:0").unwrap();
+    node.set_require_write_tokens(true);
+    node.store_record(key.clone(), Record::Plain(b"value".to_vec()), None, None, None)
+        .unwrap();
+
+    let mut other = local_node(&[]);
+    other.set_require_write_tokens(true);
+    let other_id = other.id().clone();
+    let other_address = other.address().unwrap();
+    let other_solution = other.puzzle_solution().clone();
+
+    // Tokens from nodes we didn't ask are ignored.
+    let token = other.tokens.issue(&node.address().unwrap().ip());
+    let response = rpc::FindValueResponse::CloserNodes(key.clone(), vec![], false);
+    let message = rpc::ResponseKind::FindValue(response, token);
+    let message = rpc::RPCMessage::new(other_id.clone(), rpc::MessageKind::Response(message));
+    other.send_message(node.id().clone(), node.address().unwrap(), message).unwrap();
+    node.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    node.recv_message().unwrap();
+    assert!(node.write_token_for(&other_id).is_none());
+
+    // The handoff waits until the response to our request brings a token.
+    let other = serve(other);
+    node.note_node(&other_id, &other_address, &other_solution);
+    assert_eq!(node.pending_handoffs.len(), 1);
+    node.recv_message().unwrap();
+    assert!(node.write_token_for(&other_id).is_some());
+    node.flush_handoffs();
+    assert!(node.pending_handoffs.is_empty());
+
+    let other = other.join().unwrap();
+    assert_eq!(other.store.peek(&key), Some(&Record::Plain(b"value".to_vec())));
+}
+
+#[test]
+fn leaving_hands_records_off_to_neighbours() {
+    let neighbours = (0..2)
+        .map(|_| {
+            let mut node: Node = local_node(&[]);
+            node.set_require_write_tokens(true);
+            node
+        })
+        .collect::<Vec<_>>();
+
+    let mut node = local_node(&neighbours.iter().collect::<Vec<_>>());
+    node.set_require_write_tokens(true);
+    let keys = vec![storage::hash(b"first"), storage::hash(b"second")];
+    for key in &keys {
+        node.store_record(key.clone(), Record::Plain(b"value".to_vec()), None, None, None)
+            .unwrap();
+    }
+
+    let neighbours = neighbours.into_iter().map(serve).collect::<Vec<_>>();
+    let report = node.leave(true).unwrap();
+    assert!(report.lost.is_empty());
+    assert_eq!(report.handed_off.len(), keys.len());
+    assert!(report.handed_off.iter().all(|&(_, ref nodes)| nodes.len() == 2));
+    assert_eq!(report.notified, 2);
+
+    for neighbour in neighbours {
+        let neighbour = neighbour.join().unwrap();
+        for key in &keys {
+            assert!(neighbour.store.peek(key).is_some());
+        }
+    }
+}
+
+#[test]
+fn handoffs_give_up_on_targets_that_send_no_token() {
+    let mut rng = rand::OsRng::new().unwrap();
+    let key = storage::hash(b"key");
+    let mut node = Node::new("127.0.0.1:0").unwrap();
+    node.set_require_write_tokens(true);
+    node.store_record(key.clone(), Record::Plain(b"value".to_vec()), None, None, None)
+        .unwrap();
+
+    let silent = NodeId::random(&mut rng);
+    node.note_node(&silent, &"127.0.0.1:9".parse().unwrap(), &NodeId::random(&mut rng));
+    assert_eq!(node.pending_handoffs.len(), 1);
+    assert!(node.is_awaiting_token(&silent));
+
+    // Still waiting for the token, so the handoff stays queued.
+    node.flush_handoffs();
+    assert_eq!(node.pending_handoffs.len(), 1);
+
+    let asked_at = Instant::now() - Duration::from_secs(TOKEN_REQUEST_SECS);
+    node.token_requests.insert(silent, asked_at);
+    node.flush_handoffs();
+    assert!(node.pending_handoffs.is_empty());
+    assert!(node.handoff_targets.is_empty());
+}
+
+#[test]
+fn unverified_addresses_get_bounded_responses() {
+    let mut rng = rand::OsRng::new().unwrap();
+    let mut server: Node = local_node(&[]);
+    let discard = "127.0.0.1:9".parse().unwrap();
+    for _ in 0..K {
+        server.note_node(&NodeId::random(&mut rng), &discard, &NodeId::random(&mut rng));
+    }
+    let server_id = server.id().clone();
+    let server_address = server.address().unwrap();
+    let known = server.find_k_known_nodes_closer_to(&server_id).len();
+
+    let mut client: Node = local_node(&[]);
+    client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let server = serve(server);
+
+    // A response from another node than the one we asked verifies nothing.
+    let ping = rpc::RPCMessage::new(client.id().clone(),
+                                    rpc::MessageKind::Request(rpc::RequestKind::Ping));
+    client.send_message(NodeId::random(&mut rng), server_address, ping.clone()).unwrap();
+    client.recv_message().unwrap();
+    assert!(!client.is_verified(&server_address));
+    client.send_message(server_id.clone(), server_address, ping).unwrap();
+    client.recv_message().unwrap();
+    assert!(client.is_verified(&server_address));
+
+    // Closer nodes are cut short until the request carries a token, dropping
+    // the farthest from the key.
+    let key = storage::hash(b"key");
+    let mut closer_nodes = vec![];
+    for _ in 0..2 {
+        let request = rpc::RequestKind::FindValue(key.clone(), None);
+        let request = rpc::RPCMessage::new(client.id().clone(),
+                                           rpc::MessageKind::Request(request));
+        client.send_message(server_id.clone(), server_address, request).unwrap();
+        match client.recv_message().unwrap().1.kind {
+            rpc::MessageKind::Response(rpc::ResponseKind::FindValue(
+                rpc::FindValueResponse::CloserNodes(_, nodes, truncated), _)) => {
+                let ids = nodes.iter().map(|e| e.id().clone()).collect::<Vec<_>>();
+                closer_nodes.push((ids, truncated))
+            }
+            kind => panic!("Unexpected response {:?}", kind),
+        }
+    }
+    let (mut all, truncated) = closer_nodes.pop().unwrap();
+    assert!(!truncated && all.len() == known);
+    let (cut_short, truncated) = closer_nodes.pop().unwrap();
+    assert!(truncated && cut_short.len() < known);
+    all.sort_by_key(|id| key.xor(id));
+    assert_eq!(cut_short[..], all[..cut_short.len()]);
+    server.join().unwrap();
+}
+
+#[test]
+fn lookups_ask_again_for_the_nodes_left_out() {
+    let mut holder: Node = local_node(&[]);
+    // Nodes that never answer, which the server knows besides the holder, and
+    // the client once it gets its request, making up `K` nodes.
+    let others = (2..K).map(|_| local_node(&[])).collect::<Vec<_>>();
+    let mut known = others.iter().collect::<Vec<_>>();
+    known.push(&holder);
+    let server = local_node(&known);
+    let mut client = local_node(&[&server]);
+
+    // A key the holder is the farthest of them from, so that it's left out of
+    // the response to an unverified address.
+    let key = (0..)
+        .map(|i: u32| storage::hash(format!("key {}", i).as_bytes()))
+        .find(|key| {
+            let farthest = key.xor(holder.id());
+            key.xor(client.id()) < farthest &&
+            others.iter().all(|o| key.xor(o.id()) < farthest)
+        })
+        .unwrap();
+    holder.store_record(key.clone(), Record::Plain(b"value".to_vec()), None, None, None)
+        .unwrap();
+
+    let holder = serve(holder);
+    let server = serve(server);
+    assert_eq!(client.find(key).unwrap(), Some(b"value".to_vec()));
+    let server = server.join().unwrap();
+    assert_eq!(server.rate_limiter().stats(RequestClass::FindValue).allowed, 2);
+    holder.join().unwrap();
+}
+
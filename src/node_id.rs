@@ -20,11 +20,15 @@
 //! The node ids for the network.
 
 use rand::Rng;
+use sha2::{Digest, Sha256};
 use std::cmp::{Ord, PartialOrd, Ordering};
 use std::fmt;
 
 /// A node id, with 160 bits.
-#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+///
+/// Ids are ordered by their raw bytes, which is not the same as ordering them
+/// by distance, see `xor`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct NodeId {
     id: [u8; 20],
 }
@@ -68,6 +72,15 @@ impl NodeId {
         Some(id)
     }
 
+    /// Derives an id from the SHA-256 digest of `data`, truncated to the 160
+    /// bits of the id space.
+    pub fn digest(data: &[u8]) -> Self {
+        let digest = Sha256::digest(data);
+        let mut id = [0; 20];
+        id.copy_from_slice(&digest[..20]);
+        NodeId { id }
+    }
+
     /// Create a new random `NodeId`.
     pub fn random<R>(rng: &mut R) -> Self
         where R: Rng,
@@ -77,6 +90,23 @@ impl NodeId {
         id
     }
 
+    /// Gets the raw bytes of this id.
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.id
+    }
+
+    /// Returns the number of leading zero bits of this id.
+    pub fn leading_zeros(&self) -> u32 {
+        let mut zeros = 0;
+        for byte in &self.id {
+            zeros += byte.leading_zeros();
+            if *byte != 0 {
+                break;
+            }
+        }
+        zeros
+    }
+
     /// XOR this id with `other`, in order to compute the distance.
     pub fn xor(&self, other: &Self) -> Distance {
         let mut ret = self.clone();
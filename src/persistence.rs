@@ -0,0 +1,373 @@
+/*
+ * Kademlia.rs - A WIP Kademlia algorithm implementation in Rust.
+ *
+ * Copyright (C) 2017 Emilio Cobos Álvarez <emilio@crisal.io>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! An append-only log on disk, that lets a store survive restarts.
+//!
+//! Every change to the store is appended to the log as a frame holding the
+//! length of the serialized change, its CRC-32, the CRC-32 of the length and
+//! checksum themselves, and the change itself. When the log is opened again,
+//! it's replayed to rebuild the store. A crash in the middle of a write leaves
+//! a truncated or corrupt frame at the end, which is detected through the
+//! checksums and discarded. A corrupt frame anywhere else
+//! means the log was damaged some other way, and opening it fails rather than
+//! silently losing the changes after it.
+//!
+//! Since the log only grows, it's compacted every now and then, rewriting it
+//! with the live records only. The new log is written to a temporary file
+//! which then replaces the old one, so a crash while compacting loses nothing.
+
+use bincode;
+use crc32fast;
+use record::Record;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use storage::Source;
+
+/// The size of the header of each frame: the length and the checksum of the
+/// payload, and the checksum of both, so that a damaged length can't pass for
+/// a frame that runs past the end of the log.
+const FRAME_HEADER_SIZE: usize = 12;
+
+/// A record as written to disk, along with its metadata.
+///
+/// Times are in milliseconds since the Unix epoch, since the monotonic clock
+/// the store uses doesn't survive restarts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredRecord<V = Vec<u8>> {
+    /// The record itself.
+    pub record: Record<V>,
+    /// Who this record is accounted to.
+    pub source: Source,
+    /// When this record was stored.
+    pub stored_at: u64,
+    /// When this record expires, if ever.
+    pub expires_at: Option<u64>,
+}
+
+/// A change to the store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LogEntry<K, V = Vec<u8>> {
+    /// A record was stored, replacing the existing one, if any.
+    Put(K, StoredRecord<V>),
+    /// A record was removed or evicted.
+    Delete(K),
+    /// A record expired.
+    Expire(K),
+}
+
+/// An append-only log of changes to a store.
+pub struct Log {
+    /// The path of the log.
+    path: PathBuf,
+    /// The log itself, opened for appending.
+    file: File,
+    /// The number of entries in the log.
+    entries: usize,
+}
+
+impl Log {
+    /// Opens the log at `path`, creating it if it doesn't exist, and returns
+    /// it along with all the valid entries in it, in order.
+    ///
+    /// If the log ends with a truncated or corrupt entry, like after a crash,
+    /// the log is truncated right before it. A corrupt entry followed by more
+    /// entries is an `InvalidData` error.
+    pub fn open<K, V, P>(path: P) -> io::Result<(Self, Vec<LogEntry<K, V>>)>
+        where K: Serialize + Deserialize,
+              V: Serialize + Deserialize,
+              P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let mut contents = vec![];
+        file.read_to_end(&mut contents)?;
+
+        let mut entries = vec![];
+        let mut offset = 0;
+        loop {
+            match read_frame(&contents[offset..]) {
+                Frame::Entry(entry, len) => {
+                    entries.push(entry);
+                    offset += len;
+                }
+                Frame::Corrupt(len) if offset + len < contents.len() => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("Corrupt entry at byte {} of {:?}",
+                                                      offset, path)));
+                }
+                Frame::Corrupt(..) | Frame::Truncated => break,
+            }
+        }
+
+        if offset != contents.len() {
+            warn!("Discarding {} bytes at the end of {:?}, probably from a crash",
+                  contents.len() - offset, path);
+            file.set_len(offset as u64)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        let log = Log {
+            path: path,
+            file: file,
+            entries: entries.len(),
+        };
+        Ok((log, entries))
+    }
+
+    /// Gets the number of entries in the log, which is what compaction
+    /// reduces.
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    /// Returns whether the log is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Appends an entry to the log.
+    ///
+    /// The entry reaches the operating system right away, so it survives the
+    /// process crashing, but see `sync` for surviving the machine crashing.
+    pub fn append<K, V>(&mut self, entry: &LogEntry<K, V>) -> io::Result<()>
+        where K: Serialize,
+              V: Serialize,
+    {
+        self.file.write_all(&frame(entry))?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Flushes the log to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Replaces the whole log with `entries`, atomically.
+    pub fn rewrite<K, V>(&mut self, entries: &[LogEntry<K, V>]) -> io::Result<()>
+        where K: Serialize,
+              V: Serialize,
+    {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            for entry in entries {
+                tmp.write_all(&frame(entry))?;
+            }
+            tmp.sync_all()?;
+        }
+
+        fs::rename(&tmp_path, &self.path)?;
+        sync_dir(&self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.entries = entries.len();
+        Ok(())
+    }
+}
+
+/// Flushes the directory holding `path` to disk, so that a rename into it
+/// survives the machine crashing.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened as files elsewhere, and renames are durable
+/// once they return.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// The result of reading a frame.
+enum Frame<K, V> {
+    /// A valid entry, along with the length of its frame.
+    Entry(LogEntry<K, V>, usize),
+    /// A frame that claims the attached length, but whose contents don't match
+    /// its checksum or don't deserialize, or just a header that doesn't match
+    /// its checksum.
+    Corrupt(usize),
+    /// A frame that ends past the end of the data.
+    Truncated,
+}
+
+/// Serializes an entry into a frame.
+fn frame<K, V>(entry: &LogEntry<K, V>) -> Vec<u8>
+    where K: Serialize,
+          V: Serialize,
+{
+    let payload = bincode::serialize(entry, bincode::Infinite)
+        .expect("Serializing to a Vec shouldn't fail");
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&u32_to_bytes(payload.len() as u32));
+    frame.extend_from_slice(&u32_to_bytes(crc32fast::hash(&payload)));
+    let header_checksum = crc32fast::hash(&frame);
+    frame.extend_from_slice(&u32_to_bytes(header_checksum));
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+/// Reads the frame at the start of `data`.
+fn read_frame<K, V>(data: &[u8]) -> Frame<K, V>
+    where K: Deserialize,
+          V: Deserialize,
+{
+    if data.len() < FRAME_HEADER_SIZE {
+        return Frame::Truncated;
+    }
+
+    if crc32fast::hash(&data[0..8]) != u32_from_bytes(&data[8..12]) {
+        return Frame::Corrupt(FRAME_HEADER_SIZE);
+    }
+
+    let len = u32_from_bytes(&data[0..4]) as usize;
+    let checksum = u32_from_bytes(&data[4..8]);
+    let payload = match data[FRAME_HEADER_SIZE..].get(..len) {
+        Some(payload) => payload,
+        None => return Frame::Truncated,
+    };
+    if crc32fast::hash(payload) != checksum {
+        return Frame::Corrupt(FRAME_HEADER_SIZE + len);
+    }
+
+    match bincode::deserialize(payload) {
+        Ok(entry) => Frame::Entry(entry, FRAME_HEADER_SIZE + len),
+        Err(..) => Frame::Corrupt(FRAME_HEADER_SIZE + len),
+    }
+}
+
+fn u32_to_bytes(n: u32) -> [u8; 4] {
+    [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+}
+
+fn u32_from_bytes(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 |
+        (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+#[test]
+fn recovers_from_truncated_log() {
+    use node_id::NodeId;
+    use rand;
+    use std::env;
+    use storage::Store;
+
+    let mut rng = rand::OsRng::new().unwrap();
+    let path = env::temp_dir().join(format!("kademlia-{}.log", NodeId::random(&mut rng)));
+    let own_id = NodeId::random(&mut rng);
+    let one = NodeId::from_bytes([1; 20]);
+    let two = NodeId::from_bytes([2; 20]);
+
+    {
+        let mut store: Store = Store::open(own_id.clone(), &path).unwrap();
+        store.insert(one.clone(), Record::Plain(vec![1]), Source::Local, None).unwrap();
+        store.insert(two.clone(), Record::Plain(vec![2]), Source::Local, None).unwrap();
+        store.remove(&one);
+    }
+
+    // Simulate a crash in the middle of a write.
+    let len = fs::metadata(&path).unwrap().len();
+    OpenOptions::new().append(true).open(&path).unwrap()
+        .write_all(&frame::<NodeId, Vec<u8>>(&LogEntry::Delete(two.clone()))[..5])
+        .unwrap();
+
+    let store: Store = Store::open(own_id, &path).unwrap();
+    assert!(store.peek(&one).is_none());
+    assert_eq!(store.peek(&two), Some(&Record::Plain(vec![2])));
+    assert!(fs::metadata(&path).unwrap().len() <= len);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn refuses_logs_corrupt_in_the_middle() {
+    use node_id::NodeId;
+    use rand;
+    use std::env;
+    use storage::Store;
+
+    let mut rng = rand::OsRng::new().unwrap();
+    let path = env::temp_dir().join(format!("kademlia-{}.log", NodeId::random(&mut rng)));
+    let own_id = NodeId::random(&mut rng);
+
+    {
+        let mut store: Store = Store::open(own_id.clone(), &path).unwrap();
+        for i in 0..3 {
+            store.insert(NodeId::from_bytes([i; 20]), Record::Plain(vec![i]), Source::Local, None)
+                .unwrap();
+        }
+    }
+
+    // Flip a bit in the payload of the first entry.
+    let mut contents = fs::read(&path).unwrap();
+    contents[FRAME_HEADER_SIZE] ^= 1;
+    fs::write(&path, &contents).unwrap();
+
+    let err = Store::<NodeId, Vec<u8>>::open(own_id, &path).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(fs::read(&path).unwrap(), contents);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn refuses_logs_with_a_corrupt_length_in_the_middle() {
+    use node_id::NodeId;
+    use rand;
+    use std::env;
+    use storage::Store;
+
+    let mut rng = rand::OsRng::new().unwrap();
+    let path = env::temp_dir().join(format!("kademlia-{}.log", NodeId::random(&mut rng)));
+    let own_id = NodeId::random(&mut rng);
+
+    {
+        let mut store: Store = Store::open(own_id.clone(), &path).unwrap();
+        for i in 0..3 {
+            store.insert(NodeId::from_bytes([i; 20]), Record::Plain(vec![i]), Source::Local, None)
+                .unwrap();
+        }
+    }
+
+    // Make the length of the second entry point past the end of the log.
+    let mut contents = fs::read(&path).unwrap();
+    let second = FRAME_HEADER_SIZE + u32_from_bytes(&contents[0..4]) as usize;
+    contents[second + 3] ^= 0x80;
+    fs::write(&path, &contents).unwrap();
+
+    let err = Store::<NodeId, Vec<u8>>::open(own_id, &path).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(fs::read(&path).unwrap(), contents);
+    fs::remove_file(&path).unwrap();
+}
+
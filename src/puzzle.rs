@@ -0,0 +1,323 @@
+/*
+ * Kademlia.rs - A WIP Kademlia algorithm implementation in Rust.
+ *
+ * Copyright (C) 2017 Emilio Cobos Álvarez <emilio@crisal.io>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The S/Kademlia crypto puzzles, used to make generating node ids expensive.
+//!
+//! There are two puzzles:
+//!
+//!  * The static puzzle: `H(H(public_key))` (that is, the hash of the node
+//!    id) must have at least `static_bits` leading zero bits. Since the id is
+//!    derived from the key, this makes generating ids expensive, and it can be
+//!    checked looking at the id only.
+//!
+//!  * The dynamic puzzle: a node must find a value `X` such that
+//!    `H(id ^ X)` has at least `dynamic_bits` leading zero bits. The solution
+//!    travels along with the id, and the difficulty can be raised over time
+//!    without invalidating every existing id.
+//!
+//! A difficulty of zero bits disables the relevant puzzle.
+//!
+//! Store and cache requests can also be required to carry a stamp, that is, a
+//! proof of work over the sender, receiver, time window, key and record: a
+//! nonce `N` such that `H(H(sender, receiver, window, key, record) ^ N)` has
+//! enough leading zero bits. This makes flooding the network with junk records
+//! expensive, since a stamp can't be reused for other nodes, nor for long.
+
+use bincode;
+use identity::Keypair;
+use node_id::NodeId;
+use rand::Rng;
+use record::Record;
+use serde::Serialize;
+use std::cmp;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The most leading zero bits a stamp can require, so that solving one always
+/// takes a bounded time.
+pub const MAX_STAMP_BITS: u32 = 32;
+
+/// The length of the time windows stamps are made for, in seconds. Stamps
+/// from the current window and the ones next to it are accepted, to allow for
+/// some clock skew.
+pub const STAMP_WINDOW_SECS: u64 = 300;
+
+/// The solution to the dynamic puzzle, the `X` in `H(id ^ X)`.
+pub type Solution = NodeId;
+
+/// The amount of leading zero bits that each of the puzzles require.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Difficulty {
+    /// The leading zero bits required by the static puzzle.
+    pub static_bits: u32,
+    /// The leading zero bits required by the dynamic puzzle.
+    pub dynamic_bits: u32,
+}
+
+impl Difficulty {
+    /// Returns whether this difficulty doesn't require any work at all.
+    pub fn is_disabled(&self) -> bool {
+        self.static_bits == 0 && self.dynamic_bits == 0
+    }
+}
+
+/// Returns whether `id` solves the static puzzle with the given difficulty.
+pub fn solves_static(id: &NodeId, bits: u32) -> bool {
+    bits == 0 || NodeId::digest(id.as_bytes()).leading_zeros() >= bits
+}
+
+/// Returns whether `solution` solves the dynamic puzzle for `id` with the
+/// given difficulty.
+pub fn solves_dynamic(id: &NodeId, solution: &Solution, bits: u32) -> bool {
+    if bits == 0 {
+        return true;
+    }
+
+    let mut xored = [0; 20];
+    for (i, (a, b)) in id.as_bytes().iter().zip(solution.as_bytes()).enumerate() {
+        xored[i] = a ^ b;
+    }
+
+    NodeId::digest(&xored).leading_zeros() >= bits
+}
+
+/// Returns whether the `id` and `solution` pair is valid with the given
+/// difficulty.
+pub fn verify(id: &NodeId, solution: &Solution, difficulty: &Difficulty) -> bool {
+    solves_static(id, difficulty.static_bits) &&
+        solves_dynamic(id, solution, difficulty.dynamic_bits)
+}
+
+/// Generates keypairs until one whose id solves the static puzzle is found.
+///
+/// Each extra bit doubles the expected amount of work.
+pub fn generate_keypair<R>(rng: &mut R, static_bits: u32) -> Keypair
+    where R: Rng,
+{
+    loop {
+        let keypair = Keypair::generate(rng);
+        if solves_static(&keypair.node_id(), static_bits) {
+            return keypair;
+        }
+    }
+}
+
+/// Finds a solution to the dynamic puzzle for `id`.
+///
+/// Each extra bit doubles the expected amount of work.
+pub fn solve_dynamic<R>(rng: &mut R, id: &NodeId, dynamic_bits: u32) -> Solution
+    where R: Rng,
+{
+    loop {
+        let solution = NodeId::random(rng);
+        if solves_dynamic(id, &solution, dynamic_bits) {
+            return solution;
+        }
+    }
+}
+
+/// A proof of work attached to a store or cache request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stamp {
+    /// The time window the stamp was made in, see `STAMP_WINDOW_SECS`.
+    pub window: u64,
+    /// The nonce that gives the hash enough leading zero bits.
+    pub nonce: u64,
+}
+
+/// The difficulty of the stamps required on store requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StampDifficulty {
+    /// The leading zero bits required for records up to a KiB.
+    pub base_bits: u32,
+    /// Whether an extra bit is required every time the size of the record
+    /// doubles past a KiB, so that the work grows along with the size.
+    pub scale_with_size: bool,
+}
+
+impl StampDifficulty {
+    /// Gets the leading zero bits required for a record of `size` bytes,
+    /// which are never more than `MAX_STAMP_BITS`.
+    pub fn bits_for(&self, size: usize) -> u32 {
+        if self.base_bits == 0 || !self.scale_with_size {
+            return cmp::min(self.base_bits, MAX_STAMP_BITS);
+        }
+        let kibs = (size / 1024 + 1) as u64;
+        cmp::min(self.base_bits.saturating_add(63 - kibs.leading_zeros()),
+                 MAX_STAMP_BITS)
+    }
+}
+
+/// Gets the time window stamps are made for now.
+fn current_window() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() / STAMP_WINDOW_SECS)
+        .unwrap_or(0)
+}
+
+/// Hashes what a stamp covers, which is then combined with each candidate
+/// nonce.
+fn stamp_prefix<K, V>(sender: &NodeId,
+                      receiver: &NodeId,
+                      window: u64,
+                      key: &K,
+                      record: &Record<V>)
+                      -> NodeId
+    where K: Serialize,
+          V: Serialize,
+{
+    let data = (sender, receiver, window, key, record);
+    NodeId::digest(&bincode::serialize(&data, bincode::Infinite)
+        .expect("Serializing to a Vec shouldn't fail"))
+}
+
+/// Returns whether `nonce` has the given difficulty for `prefix`.
+fn solves_stamp(prefix: &NodeId, nonce: u64, bits: u32) -> bool {
+    let mut data = prefix.as_bytes().to_vec();
+    for i in 0..8 {
+        data[i] ^= (nonce >> (i * 8)) as u8;
+    }
+    NodeId::digest(&data).leading_zeros() >= bits
+}
+
+/// Finds a stamp in the given time window, see `solve_stamp`.
+fn solve_stamp_in<K, V>(window: u64,
+                        sender: &NodeId,
+                        receiver: &NodeId,
+                        key: &K,
+                        record: &Record<V>,
+                        difficulty: &StampDifficulty)
+                        -> Stamp
+    where K: Serialize,
+          V: Serialize,
+{
+    let bits = difficulty.bits_for(bincode::serialized_size(record) as usize);
+    if bits == 0 {
+        return Stamp { window: window, nonce: 0 };
+    }
+
+    let prefix = stamp_prefix(sender, receiver, window, key, record);
+    let nonce = (0..).find(|nonce| solves_stamp(&prefix, *nonce, bits))
+        .expect("There should be a nonce with at most MAX_STAMP_BITS zero bits");
+    Stamp { window: window, nonce: nonce }
+}
+
+/// Finds a stamp for a request from `sender` to `receiver` to store or cache
+/// `record` under `key`, with the given difficulty, for the current time
+/// window.
+///
+/// Each extra bit doubles the expected amount of work.
+pub fn solve_stamp<K, V>(sender: &NodeId,
+                         receiver: &NodeId,
+                         key: &K,
+                         record: &Record<V>,
+                         difficulty: &StampDifficulty)
+                         -> Stamp
+    where K: Serialize,
+          V: Serialize,
+{
+    solve_stamp_in(current_window(), sender, receiver, key, record, difficulty)
+}
+
+/// Returns whether `stamp` is valid for a request from `sender` to `receiver`
+/// to store or cache `record` under `key`, with the given difficulty.
+///
+/// Stamps made for time windows other than the current one and the ones next
+/// to it are refused.
+pub fn verify_stamp<K, V>(sender: &NodeId,
+                          receiver: &NodeId,
+                          key: &K,
+                          record: &Record<V>,
+                          stamp: Stamp,
+                          difficulty: &StampDifficulty)
+                          -> bool
+    where K: Serialize,
+          V: Serialize,
+{
+    let bits = difficulty.bits_for(bincode::serialized_size(record) as usize);
+    if bits == 0 {
+        return true;
+    }
+
+    let window = current_window();
+    if stamp.window.saturating_add(1) < window || stamp.window > window.saturating_add(1) {
+        return false;
+    }
+
+    let prefix = stamp_prefix(sender, receiver, stamp.window, key, record);
+    solves_stamp(&prefix, stamp.nonce, bits)
+}
+
+#[test]
+fn puzzles() {
+    use rand;
+
+    let mut rng = rand::OsRng::new().unwrap();
+    let difficulty = Difficulty { static_bits: 6, dynamic_bits: 6 };
+
+    let keypair = generate_keypair(&mut rng, difficulty.static_bits);
+    let id = keypair.node_id();
+    let solution = solve_dynamic(&mut rng, &id, difficulty.dynamic_bits);
+    assert!(verify(&id, &solution, &difficulty));
+    assert!(verify(&id, &solution, &Difficulty::default()));
+
+    // There's a 2^-12 chance of a random pair being valid, so let's try a few.
+    let invalid = (0..3).any(|_| {
+        let id = NodeId::random(&mut rng);
+        !verify(&id, &solution, &difficulty)
+    });
+    assert!(invalid);
+}
+
+#[test]
+fn stamps() {
+    use rand;
+
+    let mut rng = rand::OsRng::new().unwrap();
+    let sender = NodeId::random(&mut rng);
+    let receiver = NodeId::random(&mut rng);
+    let other = NodeId::random(&mut rng);
+
+    let difficulty = StampDifficulty { base_bits: 8, scale_with_size: true };
+    assert_eq!(difficulty.bits_for(100), 8);
+    assert_eq!(difficulty.bits_for(5000), 10);
+    let huge = StampDifficulty { base_bits: 60, scale_with_size: true };
+    assert_eq!(huge.bits_for(1 << 30), MAX_STAMP_BITS);
+
+    let record = Record::Plain(vec![1, 2, 3]);
+    let verify = |receiver: &NodeId, record: &Record<Vec<u8>>, stamp: Stamp| {
+        verify_stamp(&sender, receiver, &sender, record, stamp, &difficulty)
+    };
+    let stamp = solve_stamp(&sender, &receiver, &sender, &record, &difficulty);
+    assert!(verify(&receiver, &record, stamp));
+
+    // A stamp is valid for another record or receiver with a 2^-8 chance.
+    assert!(!verify(&receiver, &Record::Plain(vec![4]), stamp) ||
+            !verify(&receiver, &Record::Plain(vec![5]), stamp));
+    let another = NodeId::random(&mut rng);
+    assert!(!verify(&other, &record, stamp) || !verify(&another, &record, stamp));
+
+    // Stamps from old windows are refused, whatever their work.
+    let old = solve_stamp_in(current_window() - 2,
+                             &sender,
+                             &receiver,
+                             &sender,
+                             &record,
+                             &difficulty);
+    assert!(!verify(&receiver, &record, old));
+}
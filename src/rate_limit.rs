@@ -0,0 +1,367 @@
+/*
+ * Kademlia.rs - A WIP Kademlia algorithm implementation in Rust.
+ *
+ * Copyright (C) 2017 Emilio Cobos Álvarez <emilio@crisal.io>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Rate limits for incoming requests.
+//!
+//! Requests are metered with token buckets at three scopes: per source IP
+//! address, per sender node id, and globally. Each scope has a separate budget
+//! for every kind of request, so that, for example, a flood of lookups doesn't
+//! use up the budget for pings. A request is only let through if every bucket
+//! it falls in has a token left.
+//!
+//! The source IP address is checked first, on every message we get, including
+//! responses, before its signature is verified, so that a flood from a single
+//! address is dropped before we spend any work on it.
+
+use node_id::NodeId;
+use recent::RecentMap;
+use rpc::RequestKind;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// The number of buckets a scope tracks. Past it, a bucket that has refilled
+/// is dropped to make room for a new one, and new keys are refused while
+/// there's none.
+const MAX_TRACKED_BUCKETS: usize = 4096;
+
+/// The kinds of requests that are budgeted separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestClass {
+    /// `PING` requests.
+    Ping,
+    /// `FIND_NODE` requests.
+    FindNode,
+    /// `STORE` requests.
+    Store,
+    /// `FIND_VALUE` requests.
+    FindValue,
+    /// Requests to cache a record.
+    Cache,
+    /// Notices that a node is leaving.
+    Leave,
+    /// Responses to our requests, which are only limited per source IP
+    /// address.
+    Response,
+}
+
+impl RequestClass {
+    /// Gets the class of a request.
+    pub fn of<K, V>(request: &RequestKind<K, V>) -> Self {
+        match *request {
+            RequestKind::Ping => RequestClass::Ping,
+            RequestKind::FindNode(..) => RequestClass::FindNode,
+            RequestKind::Store(..) => RequestClass::Store,
+            RequestKind::FindValue(..) => RequestClass::FindValue,
+            RequestKind::Cache(..) => RequestClass::Cache,
+            RequestKind::Leave => RequestClass::Leave,
+        }
+    }
+}
+
+/// The budget of a token bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    /// The number of requests that can be made in a burst.
+    pub burst: u32,
+    /// The number of requests per second that can be sustained.
+    pub per_second: f64,
+}
+
+/// The limits for each scope and request class. Request classes without a
+/// limit in a scope aren't limited in it.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    /// The limits for each source IP address.
+    pub per_ip: HashMap<RequestClass, Limit>,
+    /// The limits for each sender node id.
+    pub per_node: HashMap<RequestClass, Limit>,
+    /// The limits for all the requests we get.
+    pub global: HashMap<RequestClass, Limit>,
+}
+
+/// The scope whose limit a request exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// The limit of the source IP address.
+    Ip,
+    /// The limit of the sender node id.
+    Node,
+    /// The global limit.
+    Global,
+}
+
+/// A token bucket.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    /// The tokens left, which may be fractional while refilling.
+    tokens: f64,
+    /// When the bucket was last refilled.
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &Limit) -> Self {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Adds the tokens accrued since the last refill, and returns whether
+    /// the bucket is full.
+    fn refill(&mut self, limit: &Limit, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.refilled_at);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        let burst = limit.burst as f64;
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(burst);
+        self.refilled_at = now;
+        self.tokens >= burst
+    }
+}
+
+/// The token buckets of a scope, keyed by what they meter.
+#[derive(Debug)]
+struct Buckets<T: Eq + Hash> {
+    buckets: RecentMap<(T, RequestClass), TokenBucket>,
+}
+
+impl<T: Eq + Hash + Clone> Buckets<T> {
+    fn new() -> Self {
+        Buckets { buckets: RecentMap::new(MAX_TRACKED_BUCKETS) }
+    }
+
+    /// Refills the bucket for `key`, and returns whether it has a token left,
+    /// which is always the case if `class` isn't limited.
+    ///
+    /// If `key` has no bucket and we track too many already, the oldest one
+    /// that has refilled is dropped to make room for it. If every bucket is
+    /// still depleted `key` gets none, and has no token, so that whoever used
+    /// them up can't get a fresh budget by making us track new keys.
+    fn has_token(&mut self,
+                 key: &T,
+                 class: RequestClass,
+                 limits: &HashMap<RequestClass, Limit>,
+                 now: Instant)
+                 -> bool {
+        let limit = match limits.get(&class) {
+            Some(limit) => limit,
+            None => return true,
+        };
+        let key = (key.clone(), class);
+        if let Some(bucket) = self.buckets.get_mut(&key) {
+            bucket.refill(limit, now);
+            return bucket.tokens >= 1.0;
+        }
+        if self.buckets.len() >= MAX_TRACKED_BUCKETS {
+            let refilled = self.buckets.remove_oldest_where(|&(_, class), bucket| {
+                limits.get(&class).map_or(true, |limit| bucket.refill(limit, now))
+            });
+            if refilled.is_none() {
+                return false;
+            }
+        }
+        let bucket = TokenBucket::new(limit);
+        let has_token = bucket.tokens >= 1.0;
+        self.buckets.insert(key, bucket);
+        has_token
+    }
+
+    /// Takes a token from the bucket for `key`, which must have one.
+    fn take(&mut self, key: &T, class: RequestClass) {
+        if let Some(bucket) = self.buckets.get_mut(&(key.clone(), class)) {
+            bucket.tokens -= 1.0;
+        }
+    }
+}
+
+/// How many requests of a class were let through and throttled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClassStats {
+    /// The requests let through.
+    pub allowed: u64,
+    /// The requests throttled by the limit of their source IP address.
+    pub throttled_by_ip: u64,
+    /// The requests throttled by the limit of their sender.
+    pub throttled_by_node: u64,
+    /// The requests throttled by the global limit.
+    pub throttled_globally: u64,
+}
+
+/// Meters incoming requests against a set of `RateLimits`.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    by_ip: Buckets<IpAddr>,
+    by_node: Buckets<NodeId>,
+    global: Buckets<()>,
+    stats: HashMap<RequestClass, ClassStats>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter enforcing the given limits.
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            limits,
+            by_ip: Buckets::new(),
+            by_node: Buckets::new(),
+            global: Buckets::new(),
+            stats: HashMap::new(),
+        }
+    }
+
+    /// Gets the limits this rate limiter enforces.
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    /// Checks a message of `class` from `ip` against the limits of its source
+    /// address, and uses up a token of its bucket if it's let through. This is
+    /// meant to run on every message we get, before verifying its signature.
+    pub fn check_ip(&mut self, ip: &IpAddr, class: RequestClass) -> Result<(), Scope> {
+        if !self.by_ip.has_token(ip, class, &self.limits.per_ip, Instant::now()) {
+            self.stats.entry(class).or_default().throttled_by_ip += 1;
+            return Err(Scope::Ip);
+        }
+        self.by_ip.take(ip, class);
+        Ok(())
+    }
+
+    /// Checks a request of `class` from `sender`, whose source address was
+    /// let through by `check_ip`, against the limits of the sender and the
+    /// global ones, and uses up a token of each of its buckets if it's let
+    /// through.
+    ///
+    /// Returns the scope whose limit the request exceeded otherwise, in which
+    /// case no tokens are used.
+    pub fn check(&mut self, sender: &NodeId, class: RequestClass) -> Result<(), Scope> {
+        let now = Instant::now();
+        let throttled = if !self.by_node.has_token(sender, class, &self.limits.per_node, now) {
+            Some(Scope::Node)
+        } else if !self.global.has_token(&(), class, &self.limits.global, now) {
+            Some(Scope::Global)
+        } else {
+            None
+        };
+
+        let stats = self.stats.entry(class).or_default();
+        match throttled {
+            Some(Scope::Node) => stats.throttled_by_node += 1,
+            Some(_) => stats.throttled_globally += 1,
+            None => {
+                stats.allowed += 1;
+                self.by_node.take(sender, class);
+                self.global.take(&(), class);
+            }
+        }
+
+        match throttled {
+            Some(scope) => Err(scope),
+            None => Ok(()),
+        }
+    }
+
+    /// Gets how many requests of `class` were let through and throttled.
+    pub fn stats(&self, class: RequestClass) -> ClassStats {
+        self.stats.get(&class).cloned().unwrap_or_default()
+    }
+
+    /// Gets the number of buckets tracked for source IP addresses and for
+    /// senders, which never exceeds `MAX_TRACKED_BUCKETS` each.
+    pub fn tracked_buckets(&self) -> (usize, usize) {
+        (self.by_ip.buckets.len(), self.by_node.buckets.len())
+    }
+}
+
+/// Checks a request like `Node::recv_message` and `Node::handle_request` do.
+#[cfg(test)]
+fn check_request(limiter: &mut RateLimiter,
+                 ip: &IpAddr,
+                 sender: &NodeId,
+                 class: RequestClass)
+                 -> Result<(), Scope> {
+    limiter.check_ip(ip, class).and_then(|()| limiter.check(sender, class))
+}
+
+#[test]
+fn limits_each_scope_and_class_separately() {
+    let mut limits = RateLimits::default();
+    let limit = Limit { burst: 2, per_second: 0.0 };
+    limits.per_ip.insert(RequestClass::FindValue, limit);
+    limits.global.insert(RequestClass::Store, Limit { burst: 3, per_second: 0.0 });
+    let mut limiter = RateLimiter::new(limits);
+
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    let other_ip: IpAddr = "10.0.0.2".parse().unwrap();
+    let node = NodeId::from_bytes([1; 20]);
+
+    let find_value = RequestClass::FindValue;
+    assert_eq!(check_request(&mut limiter, &ip, &node, find_value), Ok(()));
+    assert_eq!(check_request(&mut limiter, &ip, &node, find_value), Ok(()));
+    assert_eq!(check_request(&mut limiter, &ip, &node, find_value), Err(Scope::Ip));
+    // Other addresses and request classes have their own budget.
+    assert_eq!(check_request(&mut limiter, &other_ip, &node, find_value), Ok(()));
+    assert_eq!(check_request(&mut limiter, &ip, &node, RequestClass::Ping), Ok(()));
+
+    for _ in 0..3 {
+        assert_eq!(check_request(&mut limiter, &other_ip, &node, RequestClass::Store), Ok(()));
+    }
+    assert_eq!(check_request(&mut limiter, &ip, &node, RequestClass::Store),
+               Err(Scope::Global));
+
+    let stats = limiter.stats(RequestClass::FindValue);
+    assert_eq!((stats.allowed, stats.throttled_by_ip), (3, 1));
+    assert_eq!(limiter.stats(RequestClass::Store).throttled_globally, 1);
+}
+
+#[test]
+fn tracked_buckets_are_bounded() {
+    let mut limits = RateLimits::default();
+    limits.per_ip.insert(RequestClass::Ping, Limit { burst: 1, per_second: 0.0 });
+    limits.per_ip.insert(RequestClass::Response, Limit { burst: 1, per_second: 1e9 });
+    let mut limiter = RateLimiter::new(limits);
+    let ip = |i: u32| IpAddr::from([10, (i >> 16) as u8, (i >> 8) as u8, i as u8]);
+
+    let first = ip(0);
+    assert_eq!(limiter.check_ip(&first, RequestClass::Ping), Ok(()));
+    assert_eq!(limiter.check_ip(&first, RequestClass::Ping), Err(Scope::Ip));
+    for i in 1..MAX_TRACKED_BUCKETS as u32 {
+        assert_eq!(limiter.check_ip(&ip(i), RequestClass::Ping), Ok(()));
+    }
+    assert_eq!(limiter.tracked_buckets().0, MAX_TRACKED_BUCKETS);
+
+    // Every bucket is depleted, so new addresses get no budget, and the ones
+    // that used theirs up don't get a fresh one.
+    let new = ip(MAX_TRACKED_BUCKETS as u32);
+    assert_eq!(limiter.check_ip(&new, RequestClass::Ping), Err(Scope::Ip));
+    assert_eq!(limiter.check_ip(&first, RequestClass::Ping), Err(Scope::Ip));
+    assert_eq!(limiter.tracked_buckets().0, MAX_TRACKED_BUCKETS);
+
+    // Buckets that refill quickly make room once they're full again, while
+    // the depleted ones are kept.
+    let mut limiter = RateLimiter::new(limiter.limits().clone());
+    assert_eq!(limiter.check_ip(&first, RequestClass::Ping), Ok(()));
+    for i in 1..MAX_TRACKED_BUCKETS as u32 {
+        assert_eq!(limiter.check_ip(&ip(i), RequestClass::Response), Ok(()));
+    }
+    assert_eq!(limiter.check_ip(&new, RequestClass::Ping), Ok(()));
+    assert_eq!(limiter.tracked_buckets().0, MAX_TRACKED_BUCKETS);
+    assert_eq!(limiter.check_ip(&first, RequestClass::Ping), Err(Scope::Ip));
+}
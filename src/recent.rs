@@ -0,0 +1,169 @@
+/*
+ * Kademlia.rs - A WIP Kademlia algorithm implementation in Rust.
+ *
+ * Copyright (C) 2017 Emilio Cobos Álvarez <emilio@crisal.io>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! A map bounded in size, which drops its oldest entries to make room for new
+//! ones.
+//!
+//! It holds the state we keep about other nodes and addresses, which anyone on
+//! the network can make us create, so that it can't grow without bounds and
+//! trimming it never takes a pass over every entry.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// A map holding at most `capacity` entries, which drops the one inserted the
+/// longest ago when a new one doesn't fit.
+#[derive(Debug, Clone)]
+pub struct RecentMap<K: Eq + Hash, V> {
+    capacity: usize,
+    /// The entries, along with the generation they were last inserted at.
+    entries: HashMap<K, (V, u64)>,
+    /// The keys in insertion order, along with the generation they were
+    /// inserted at. Keys inserted again or removed since leave stale items
+    /// behind, which are skipped.
+    order: VecDeque<(K, u64)>,
+    generation: u64,
+}
+
+impl<K: Eq + Hash + Clone, V> RecentMap<K, V> {
+    /// Creates an empty map holding at most `capacity` entries, or one if
+    /// `capacity` is zero.
+    pub fn new(capacity: usize) -> Self {
+        RecentMap {
+            capacity: if capacity == 0 { 1 } else { capacity },
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            generation: 0,
+        }
+    }
+
+    /// Gets the number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether the map is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Gets the value for `key`, if any.
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|&(ref value, _)| value)
+    }
+
+    /// Gets the value for `key` mutably, if any, without making it any newer.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.entries.get_mut(key).map(|&mut (ref mut value, _)| value)
+    }
+
+    /// Inserts a value for `key`, which becomes the newest entry, dropping the
+    /// oldest one if the map is full. Returns the previous value, if any.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.generation += 1;
+        let old = self.entries.insert(key.clone(), (value, self.generation));
+        self.order.push_back((key, self.generation));
+
+        while self.entries.len() > self.capacity {
+            self.remove_oldest();
+        }
+
+        // Drop the stale items once they outnumber the entries, which takes
+        // at least `capacity` insertions each time.
+        if self.order.len() > 2 * self.capacity {
+            let entries = &self.entries;
+            self.order.retain(|&(ref key, generation)| {
+                entries.get(key).map_or(false, |&(_, g)| g == generation)
+            });
+        }
+
+        old.map(|(value, _)| value)
+    }
+
+    /// Removes the value for `key`, if any.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.entries.remove(key).map(|(value, _)| value)
+    }
+
+    /// Removes the oldest entry for which `predicate` returns `true`, if any,
+    /// and returns its value. Unlike dropping the oldest entry, this may take
+    /// a pass over every entry.
+    pub fn remove_oldest_where<F>(&mut self, mut predicate: F) -> Option<V>
+        where F: FnMut(&K, &mut V) -> bool
+    {
+        let entries = &mut self.entries;
+        let index = self.order.iter().position(|&(ref key, generation)| {
+            match entries.get_mut(key) {
+                Some(&mut (ref mut value, g)) if g == generation => predicate(key, value),
+                _ => false,
+            }
+        });
+        let (key, _) = self.order.remove(index?)?;
+        entries.remove(&key).map(|(value, _)| value)
+    }
+
+    /// Removes the oldest entry, if any.
+    fn remove_oldest(&mut self) {
+        while let Some((key, generation)) = self.order.pop_front() {
+            if self.entries.get(&key).map_or(false, |&(_, g)| g == generation) {
+                self.entries.remove(&key);
+                return;
+            }
+        }
+    }
+}
+
+#[test]
+fn oldest_entries_are_dropped() {
+    let mut map = RecentMap::new(3);
+    for i in 0..3 {
+        map.insert(i, i);
+    }
+    // Inserting a key again makes it the newest.
+    map.insert(0, 10);
+    map.remove(&1);
+    map.insert(3, 3);
+    map.insert(4, 4);
+
+    assert_eq!(map.len(), 3);
+    assert_eq!(map.get(&0), Some(&10));
+    assert_eq!(map.get(&2), None);
+    assert_eq!(map.get(&4), Some(&4));
+
+    for _ in 0..100 {
+        map.insert(5, 5);
+    }
+    assert!(map.order.len() <= 2 * 3);
+    assert_eq!(map.len(), 3);
+}
+
+#[test]
+fn oldest_matching_entries_are_removed() {
+    let mut map = RecentMap::new(4);
+    for i in 0..4 {
+        map.insert(i, i * 10);
+    }
+    map.insert(0, 1);
+
+    assert_eq!(map.remove_oldest_where(|_, value| *value % 20 == 0), Some(20));
+    assert_eq!(map.remove_oldest_where(|_, value| *value < 5), Some(1));
+    assert_eq!(map.remove_oldest_where(|key, _| *key > 5), None);
+    assert_eq!(map.len(), 2);
+    assert_eq!(map.get(&1), Some(&10));
+}
@@ -0,0 +1,684 @@
+/*
+ * Kademlia.rs - A WIP Kademlia algorithm implementation in Rust.
+ *
+ * Copyright (C) 2017 Emilio Cobos Álvarez <emilio@crisal.io>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The different kinds of records that can be stored in the network.
+
+use bincode;
+use identity::{self, Keypair, PublicKey, Signature};
+use node_id::NodeId;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use storage::{self, Key, StoreError, Value};
+use version::{self, Versioned};
+
+/// The most announcements a record keeps, see `merge_announcements`.
+pub const MAX_ANNOUNCEMENTS: usize = 64;
+
+/// Returns the current time, in seconds since the Unix epoch.
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns the current time, in milliseconds since the Unix epoch.
+fn unix_now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs().saturating_mul(1000) + d.subsec_nanos() as u64 / 1_000_000)
+        .unwrap_or(0)
+}
+
+/// A record stored under a given key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Record<V = Vec<u8>> {
+    /// A plain value, that anyone can overwrite.
+    Plain(V),
+    /// A signed value that only the owner of a keypair can update.
+    Mutable(MutableRecord<V>),
+    /// A content-addressed value, whose key must be the hash of the value.
+    Immutable(V),
+    /// A set of announcements from different publishers, which get merged
+    /// instead of replaced.
+    Announcements(Vec<Announcement<V>>),
+    /// A signed marker that the record under the key was deleted, which
+    /// blocks older versions from being stored again until it expires.
+    Tombstone(Tombstone),
+    /// The concurrent versions of a value, see the `version` module. Outdated
+    /// versions are discarded when merging with the existing record.
+    Versioned(Vec<Versioned<V>>),
+}
+
+impl<V: Value> Record<V> {
+    /// Gets the value this record holds, or `None` for announcements and
+    /// versioned records with many siblings, which hold many.
+    pub fn value(&self) -> Option<&V> {
+        match *self {
+            Record::Plain(ref v) |
+            Record::Immutable(ref v) => Some(v),
+            Record::Mutable(ref r) => Some(&r.value),
+            Record::Versioned(ref siblings) if siblings.len() == 1 => {
+                Some(&siblings[0].value)
+            }
+            Record::Versioned(..) |
+            Record::Announcements(..) |
+            Record::Tombstone(..) => None,
+        }
+    }
+
+    /// Gets how long this record should be kept for, if it expires on its
+    /// own, like tombstones do.
+    pub fn expires_in(&self) -> Option<Duration> {
+        match *self {
+            Record::Tombstone(ref t) => {
+                Some(Duration::from_secs(t.expires_at.saturating_sub(unix_now())))
+            }
+            _ => None,
+        }
+    }
+
+    /// Merges this record with the `existing` one, which it's going to
+    /// replace.
+    ///
+    /// For most records this is just the new record, but announcements and
+    /// versions from both records are merged, and so are plain mergeable
+    /// values.
+    pub fn merge(self, existing: Option<&Record<V>>) -> Record<V> {
+        match (self, existing) {
+            (Record::Plain(new), Some(&Record::Plain(ref existing))) => {
+                let mut merged = existing.clone();
+                if merged.merge_from(&new) {
+                    Record::Plain(merged)
+                } else {
+                    Record::Plain(new)
+                }
+            }
+            (Record::Announcements(new),
+             Some(&Record::Announcements(ref existing))) => {
+                let mut merged = existing.clone();
+                merge_announcements(&mut merged, new);
+                Record::Announcements(merged)
+            }
+            (Record::Announcements(new), _) => {
+                let mut merged = vec![];
+                merge_announcements(&mut merged, new);
+                Record::Announcements(merged)
+            }
+            (Record::Versioned(new), Some(&Record::Versioned(ref existing))) => {
+                let mut merged = existing.clone();
+                version::merge_siblings(&mut merged, new);
+                Record::Versioned(merged)
+            }
+            (record, _) => record,
+        }
+    }
+
+    /// Removes the expired announcements of this record, if any.
+    pub fn remove_expired(&mut self) {
+        if let Record::Announcements(ref mut announcements) = *self {
+            let now = unix_now();
+            announcements.retain(|a| !a.is_expired_at(now));
+        }
+    }
+
+    /// Returns whether this record is valid under `key`, that is, whether a
+    /// reader can trust it.
+    pub fn verify<K: Key>(&self, key: &K) -> bool {
+        match *self {
+            Record::Plain(..) |
+            Record::Versioned(..) => true,
+            Record::Mutable(ref r) => r.key() == key.to_id() && r.verify(),
+            Record::Immutable(ref v) => storage::hash_value(v) == key.to_id(),
+            Record::Announcements(ref announcements) => {
+                announcements.iter().all(|a| a.verify(key))
+            }
+            Record::Tombstone(ref t) => t.verify(key),
+        }
+    }
+
+    /// Checks whether this record can replace `existing` under `key`.
+    ///
+    /// `cas` is the sequence number the writer expects the existing mutable
+    /// record to have, if any.
+    pub fn check_update<K: Key>(&self,
+                                key: &K,
+                                existing: Option<&Record<V>>,
+                                cas: Option<u64>)
+                        -> Result<(), StoreError> {
+        match *self {
+            Record::Plain(..) => {
+                match existing {
+                    None | Some(&Record::Plain(..)) => Ok(()),
+                    Some(&Record::Tombstone(..)) => Err(StoreError::Deleted),
+                    Some(..) => Err(StoreError::Protected),
+                }
+            }
+            Record::Tombstone(ref tombstone) => {
+                if !self.verify(key) {
+                    return Err(StoreError::InvalidSignature);
+                }
+
+                match existing {
+                    None if !tombstone.is_bound_to(key) => Err(StoreError::Protected),
+                    None | Some(&Record::Plain(..)) => Ok(()),
+                    Some(&Record::Mutable(ref existing)) => {
+                        if tombstone.public_key != existing.public_key {
+                            return Err(StoreError::Protected);
+                        }
+                        if let Some(expected) = cas {
+                            if expected != existing.seq {
+                                return Err(StoreError::CasMismatch(existing.seq));
+                            }
+                        }
+                        if tombstone.seq < existing.seq {
+                            return Err(StoreError::StaleSequence(existing.seq));
+                        }
+                        Ok(())
+                    }
+                    Some(&Record::Tombstone(ref existing)) => {
+                        // Only the deleter can renew a tombstone, unless the
+                        // key belongs to the new deleter and not the old one.
+                        if tombstone.public_key != existing.public_key {
+                            if tombstone.is_bound_to(key) && !existing.is_bound_to(key) {
+                                return Ok(());
+                            }
+                            return Err(StoreError::Protected);
+                        }
+                        if tombstone.seq < existing.seq {
+                            return Err(StoreError::StaleSequence(existing.seq));
+                        }
+                        Ok(())
+                    }
+                    Some(..) => Err(StoreError::Protected),
+                }
+            }
+            Record::Versioned(ref versions) => {
+                if versions.iter().any(|v| v.clock.len() > version::MAX_WRITERS) {
+                    return Err(StoreError::TooManyVersions);
+                }
+
+                let siblings = match existing {
+                    Some(&Record::Versioned(ref existing)) => {
+                        let mut merged = existing.clone();
+                        version::merge_siblings(&mut merged, versions.iter().cloned());
+                        merged.len()
+                    }
+                    None | Some(&Record::Plain(..)) => versions.len(),
+                    Some(&Record::Tombstone(..)) => return Err(StoreError::Deleted),
+                    Some(..) => return Err(StoreError::Protected),
+                };
+                if siblings > version::MAX_SIBLINGS {
+                    return Err(StoreError::TooManyVersions);
+                }
+                Ok(())
+            }
+            Record::Announcements(..) => {
+                if !self.verify(key) {
+                    return Err(StoreError::InvalidSignature);
+                }
+
+                match existing {
+                    None |
+                    Some(&Record::Plain(..)) |
+                    Some(&Record::Announcements(..)) => Ok(()),
+                    Some(&Record::Tombstone(..)) => Err(StoreError::Deleted),
+                    Some(..) => Err(StoreError::Protected),
+                }
+            }
+            Record::Immutable(..) => {
+                if !self.verify(key) {
+                    return Err(StoreError::HashMismatch);
+                }
+
+                match existing {
+                    Some(&Record::Mutable(..)) => Err(StoreError::Protected),
+                    _ => Ok(()),
+                }
+            }
+            Record::Mutable(ref record) => {
+                if !self.verify(key) {
+                    return Err(StoreError::InvalidSignature);
+                }
+
+                let existing = match existing {
+                    Some(&Record::Mutable(ref existing)) => existing,
+                    Some(&Record::Immutable(..)) => {
+                        return Err(StoreError::Protected)
+                    }
+                    Some(&Record::Tombstone(ref tombstone)) => {
+                        // Only newer versions from the owner can bring the
+                        // record back, but whoever else deleted something
+                        // planted at the key of the owner can't keep it away.
+                        if tombstone.public_key != record.public_key {
+                            if tombstone.is_bound_to(key) {
+                                return Err(StoreError::Deleted);
+                            }
+                            return Ok(());
+                        }
+                        if record.seq <= tombstone.seq {
+                            return Err(StoreError::StaleSequence(tombstone.seq));
+                        }
+                        return Ok(());
+                    }
+                    _ => return Ok(()),
+                };
+
+                if let Some(expected) = cas {
+                    if expected != existing.seq {
+                        return Err(StoreError::CasMismatch(existing.seq));
+                    }
+                }
+
+                if record.seq < existing.seq ||
+                   (record.seq == existing.seq && record.value != existing.value) {
+                    return Err(StoreError::StaleSequence(existing.seq));
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A mutable record, as described in [BEP 44][bep44].
+///
+/// The key of the record is derived from the public key of its owner and an
+/// optional salt, so that a single keypair can own many records. Every update
+/// needs to be signed by the owner, and carry a bigger sequence number than the
+/// last one.
+///
+/// [bep44]: http://bittorrent.org/beps/bep_0044.html
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MutableRecord<V = Vec<u8>> {
+    /// The public key of the owner of this record.
+    pub public_key: PublicKey,
+    /// The salt used to derive the key, possibly empty.
+    pub salt: Vec<u8>,
+    /// The sequence number of this record.
+    pub seq: u64,
+    /// The actual value.
+    pub value: V,
+    /// The signature of the salt, sequence number and value.
+    pub signature: Signature,
+}
+
+impl<V: Value> MutableRecord<V> {
+    /// Creates a new record, signing it with `keypair`.
+    pub fn new(keypair: &Keypair, salt: Vec<u8>, seq: u64, value: V) -> Self {
+        let mut record = MutableRecord {
+            public_key: keypair.public_key(),
+            salt: salt,
+            seq: seq,
+            value: value,
+            signature: vec![],
+        };
+        record.signature = keypair.sign(&record.signed_data());
+        record
+    }
+
+    /// Derives the key a record from the owner of `public_key` with `salt` is
+    /// stored at.
+    pub fn key_for(public_key: &PublicKey, salt: &[u8]) -> NodeId {
+        let mut data = public_key.to_vec();
+        data.extend_from_slice(salt);
+        NodeId::digest(&data)
+    }
+
+    /// Gets the key this record is stored at.
+    pub fn key(&self) -> NodeId {
+        Self::key_for(&self.public_key, &self.salt)
+    }
+
+    /// The bytes covered by the signature of this record.
+    fn signed_data(&self) -> Vec<u8> {
+        let data = (&self.salt, &self.seq, &self.value.to_bytes());
+        bincode::serialize(&data, bincode::Infinite)
+            .expect("Serializing to a Vec shouldn't fail")
+    }
+
+    /// Returns whether the signature of this record is valid.
+    pub fn verify(&self) -> bool {
+        identity::verify(&self.public_key, &self.signed_data(), &self.signature)
+    }
+}
+
+/// An announcement of a publisher under a given key, like a host announcing
+/// that it provides some content, or a peer announcing itself for a torrent.
+///
+/// Many announcements can live under the same key. There's at most one for
+/// each publisher, and each of them expires on its own.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Announcement<V = Vec<u8>> {
+    /// The public key of the publisher.
+    pub public_key: PublicKey,
+    /// Whatever the publisher wants to tell about itself, like an address.
+    pub value: V,
+    /// When this announcement was made, in milliseconds since the Unix epoch.
+    /// A later announcement replaces an earlier one from the same publisher.
+    pub issued_at: u64,
+    /// When this announcement expires, in seconds since the Unix epoch.
+    pub expires_at: u64,
+    /// The signature of the key, value, issue and expiration times.
+    pub signature: Signature,
+}
+
+impl<V: Value> Announcement<V> {
+    /// Creates an announcement under `key`, valid for `ttl`, and signs it with
+    /// `keypair`.
+    pub fn new<K>(keypair: &Keypair, key: &K, value: V, ttl: Duration) -> Self
+        where K: Key,
+    {
+        let mut announcement = Announcement {
+            public_key: keypair.public_key(),
+            value: value,
+            issued_at: unix_now_ms(),
+            expires_at: unix_now().saturating_add(ttl.as_secs()),
+            signature: vec![],
+        };
+        announcement.signature = keypair.sign(&announcement.signed_data(key));
+        announcement
+    }
+
+    /// Gets the id of the publisher of this announcement.
+    pub fn publisher(&self) -> NodeId {
+        identity::node_id_for(&self.public_key)
+    }
+
+    /// Returns whether this announcement is expired.
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(unix_now())
+    }
+
+    fn is_expired_at(&self, now: u64) -> bool {
+        self.expires_at <= now
+    }
+
+    /// The bytes covered by the signature of this announcement. The key is
+    /// included so that announcements can't be moved to other keys.
+    fn signed_data<K: Key>(&self, key: &K) -> Vec<u8> {
+        let data = (key, &self.value.to_bytes(), &self.issued_at, &self.expires_at);
+        bincode::serialize(&data, bincode::Infinite)
+            .expect("Serializing to a Vec shouldn't fail")
+    }
+
+    /// Returns whether the signature of this announcement under `key` is
+    /// valid.
+    pub fn verify<K: Key>(&self, key: &K) -> bool {
+        identity::verify(&self.public_key, &self.signed_data(key), &self.signature)
+    }
+}
+
+/// A deletion marker for a key, signed by whoever deleted it.
+///
+/// Plain records can be deleted by anyone, since anyone can overwrite them
+/// anyway, but mutable records can only be deleted by their owner, with a
+/// sequence number not older than the one of the record. Keys without a record
+/// can only be deleted by the owner of the mutable records they're derived
+/// from. Content-addressed records and announcements can't be deleted, and
+/// expire on their own.
+///
+/// Nodes may keep tombstones for less time than they ask for, see
+/// `Node::set_max_tombstone_ttl`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    /// The public key of whoever deleted the record.
+    pub public_key: PublicKey,
+    /// The salt of the mutable record being deleted, which binds keys without
+    /// a record to the public key, see `MutableRecord::key_for`.
+    pub salt: Vec<u8>,
+    /// The sequence number of the deletion. Mutable records need a bigger one
+    /// to be stored again.
+    pub seq: u64,
+    /// When this tombstone expires, in seconds since the Unix epoch.
+    pub expires_at: u64,
+    /// The signature of the key, salt, sequence number and expiration time.
+    pub signature: Signature,
+}
+
+impl Tombstone {
+    /// Creates a tombstone for `key`, which holds the mutable record with
+    /// `salt`, if any, valid for `ttl`, and signs it with `keypair`.
+    pub fn new<K>(keypair: &Keypair,
+                  key: &K,
+                  salt: Vec<u8>,
+                  seq: u64,
+                  ttl: Duration)
+                  -> Self
+        where K: Key,
+    {
+        let mut tombstone = Tombstone {
+            public_key: keypair.public_key(),
+            salt: salt,
+            seq: seq,
+            expires_at: unix_now().saturating_add(ttl.as_secs()),
+            signature: vec![],
+        };
+        tombstone.signature = keypair.sign(&tombstone.signed_data(key));
+        tombstone
+    }
+
+    /// The bytes covered by the signature of this tombstone.
+    fn signed_data<K: Key>(&self, key: &K) -> Vec<u8> {
+        let data = (key, &self.salt, &self.seq, &self.expires_at);
+        bincode::serialize(&data, bincode::Infinite)
+            .expect("Serializing to a Vec shouldn't fail")
+    }
+
+    /// Returns whether the signature of this tombstone under `key` is valid.
+    pub fn verify<K: Key>(&self, key: &K) -> bool {
+        identity::verify(&self.public_key, &self.signed_data(key), &self.signature)
+    }
+
+    /// Returns whether `key` is the one the mutable records of the deleter
+    /// with our salt are stored under.
+    pub fn is_bound_to<K: Key>(&self, key: &K) -> bool {
+        MutableRecord::<Vec<u8>>::key_for(&self.public_key, &self.salt) == key.to_id()
+    }
+}
+
+/// Merges `new` announcements into `announcements`, keeping only the latest
+/// one for each publisher, and dropping expired ones.
+///
+/// At most `MAX_ANNOUNCEMENTS` are kept, dropping the ones that expire the
+/// soonest, so that publishers with fresh keypairs can't grow the record past
+/// what fits in a message.
+pub fn merge_announcements<V, I>(announcements: &mut Vec<Announcement<V>>, new: I)
+    where V: Value,
+          I: IntoIterator<Item = Announcement<V>>,
+{
+    for announcement in new {
+        let existing = announcements.iter()
+            .position(|a| a.public_key == announcement.public_key);
+        match existing {
+            Some(i) => {
+                if announcements[i].issued_at < announcement.issued_at {
+                    announcements[i] = announcement;
+                }
+            }
+            None => announcements.push(announcement),
+        }
+    }
+
+    let now = unix_now();
+    announcements.retain(|a| !a.is_expired_at(now));
+    while announcements.len() > MAX_ANNOUNCEMENTS {
+        let soonest = announcements.iter()
+            .enumerate()
+            .min_by_key(|&(_, a)| a.expires_at)
+            .map(|(i, _)| i)
+            .expect("There should be announcements past the limit");
+        announcements.remove(soonest);
+    }
+}
+
+#[test]
+fn mutable_record_updates() {
+    use rand;
+
+    let mut rng = rand::OsRng::new().unwrap();
+    let keypair = Keypair::generate(&mut rng);
+
+    let first: MutableRecord = MutableRecord::new(&keypair, b"config".to_vec(), 1, vec![1]);
+    let key = first.key();
+    let first = Record::Mutable(first);
+    assert!(first.check_update(&key, None, None).is_ok());
+
+    let second = MutableRecord::new(&keypair, b"config".to_vec(), 2, vec![2]);
+    let second = Record::Mutable(second);
+    assert!(second.check_update(&key, Some(&first), Some(1)).is_ok());
+    assert_eq!(second.check_update(&key, Some(&first), Some(0)),
+               Err(StoreError::CasMismatch(1)));
+    assert_eq!(first.check_update(&key, Some(&second), None),
+               Err(StoreError::StaleSequence(2)));
+    assert_eq!(Record::Plain(vec![3]).check_update(&key, Some(&first), None),
+               Err(StoreError::Protected));
+
+    let mut forged = MutableRecord::new(&keypair, b"config".to_vec(), 3, vec![3]);
+    forged.value = vec![4];
+    assert_eq!(Record::Mutable(forged).check_update(&key, Some(&second), None),
+               Err(StoreError::InvalidSignature));
+}
+
+#[test]
+fn tombstones_block_older_versions() {
+    use rand;
+
+    let mut rng = rand::OsRng::new().unwrap();
+    let owner = Keypair::generate(&mut rng);
+    let other = Keypair::generate(&mut rng);
+
+    let record: MutableRecord = MutableRecord::new(&owner, vec![], 1, vec![1]);
+    let key = record.key();
+    let record = Record::Mutable(record);
+
+    let ttl = Duration::from_secs(60);
+    let forged = Record::Tombstone(Tombstone::new(&other, &key, vec![], 1, ttl));
+    assert_eq!(forged.check_update(&key, Some(&record), None),
+               Err(StoreError::Protected));
+
+    // Keys without a record can only be deleted by whoever they belong to.
+    assert_eq!(forged.check_update(&key, None, None), Err(StoreError::Protected));
+    let salted: Record = Record::Tombstone(Tombstone::new(&owner, &key, b"salt".to_vec(), 1, ttl));
+    assert_eq!(salted.check_update(&key, None, None), Err(StoreError::Protected));
+    let plain = storage::hash(b"plain");
+    let anyone: Record = Record::Tombstone(Tombstone::new(&other, &plain, vec![], 1, ttl));
+    assert!(anyone.check_update(&plain, Some(&Record::Plain(vec![1])), None).is_ok());
+
+    let tombstone = Record::Tombstone(Tombstone::new(&owner, &key, vec![], 1, ttl));
+    assert!(tombstone.check_update(&key, None, None).is_ok());
+    assert!(tombstone.check_update(&key, Some(&record), None).is_ok());
+    assert_eq!(record.check_update(&key, Some(&tombstone), None),
+               Err(StoreError::StaleSequence(1)));
+    assert_eq!(Record::Plain(vec![2]).check_update(&key, Some(&tombstone), None),
+               Err(StoreError::Deleted));
+
+    let newer = Record::Mutable(MutableRecord::new(&owner, vec![], 2, vec![2]));
+    assert!(newer.check_update(&key, Some(&tombstone), None).is_ok());
+}
+
+#[test]
+fn tombstones_can_only_be_replaced_by_their_owner() {
+    use rand;
+
+    let mut rng = rand::OsRng::new().unwrap();
+    let owner = Keypair::generate(&mut rng);
+    let other = Keypair::generate(&mut rng);
+    let key = MutableRecord::<Vec<u8>>::key_for(&owner.public_key(), &[]);
+    let ttl = Duration::from_secs(60);
+
+    let tombstone: Record = Record::Tombstone(Tombstone::new(&owner, &key, vec![], 1, ttl));
+    let forged = Record::Tombstone(Tombstone::new(&other, &key, vec![], u64::max_value(), ttl));
+    assert_eq!(forged.check_update(&key, Some(&tombstone), None),
+               Err(StoreError::Protected));
+    let renewed = Record::Tombstone(Tombstone::new(&owner, &key, vec![], 2, ttl));
+    assert!(renewed.check_update(&key, Some(&tombstone), None).is_ok());
+
+    // Deleting a record planted at the key of the owner doesn't lock it out.
+    let planted = Record::Plain(vec![1]);
+    assert!(forged.check_update(&key, Some(&planted), None).is_ok());
+    let record = Record::Mutable(MutableRecord::new(&owner, vec![], 1, vec![1]));
+    assert!(record.check_update(&key, Some(&forged), None).is_ok());
+    assert!(tombstone.check_update(&key, Some(&forged), None).is_ok());
+    assert_eq!(forged.check_update(&key, Some(&tombstone), None),
+               Err(StoreError::Protected));
+}
+
+#[test]
+fn later_announcements_replace_earlier_ones() {
+    use rand;
+
+    let mut rng = rand::OsRng::new().unwrap();
+    let keypair = Keypair::generate(&mut rng);
+    let other = Keypair::generate(&mut rng);
+    let key = storage::hash(b"service");
+    let announce = |keypair: &Keypair, value: &[u8], issued_at: u64, ttl: u64| {
+        let mut announcement = Announcement::new(keypair, &key, value.to_vec(),
+                                                 Duration::from_secs(ttl));
+        announcement.issued_at = issued_at;
+        announcement.signature = keypair.sign(&announcement.signed_data(&key));
+        announcement
+    };
+
+    // A later announcement with a shorter lifetime still replaces the earlier
+    // one, and an earlier one arriving late doesn't bring it back.
+    let first = announce(&keypair, b"first", 1, 3600);
+    let second = announce(&keypair, b"second", 2, 60);
+    let mut announcements = vec![];
+    merge_announcements(&mut announcements, vec![first.clone()]);
+    merge_announcements(&mut announcements, vec![second.clone()]);
+    assert_eq!(announcements, vec![second.clone()]);
+    merge_announcements(&mut announcements, vec![first]);
+    assert_eq!(announcements, vec![second.clone()]);
+
+    let another = announce(&other, b"another", 1, 60);
+    let expired = announce(&other, b"expired", 3, 0);
+    merge_announcements(&mut announcements, vec![another.clone()]);
+    assert_eq!(announcements, vec![second.clone(), another.clone()]);
+    merge_announcements(&mut announcements, vec![expired]);
+    assert_eq!(announcements, vec![second.clone()]);
+    assert!(second.verify(&key));
+    assert!(!second.verify(&storage::hash(b"other service")));
+
+    let forever = Announcement::new(&keypair, &key, vec![], Duration::from_secs(u64::max_value()));
+    assert_eq!(forever.expires_at, u64::max_value());
+}
+
+#[test]
+fn announcements_per_key_are_bounded() {
+    use rand;
+
+    let mut rng = rand::OsRng::new().unwrap();
+    let key = storage::hash(b"service");
+    let mut announce = |ttl: u64| {
+        let keypair = Keypair::generate(&mut rng);
+        Announcement::new(&keypair, &key, vec![], Duration::from_secs(ttl))
+    };
+
+    let soonest = announce(60);
+    let mut announcements = vec![soonest.clone()];
+    merge_announcements(&mut announcements,
+                        (0..MAX_ANNOUNCEMENTS).map(|_| announce(3600)));
+    assert_eq!(announcements.len(), MAX_ANNOUNCEMENTS);
+    assert!(!announcements.contains(&soonest));
+
+    // Records stored over nothing are bounded too.
+    let flood = (0..MAX_ANNOUNCEMENTS + 1).map(|_| announce(3600)).collect();
+    match Record::Announcements(flood).merge(None) {
+        Record::Announcements(merged) => assert_eq!(merged.len(), MAX_ANNOUNCEMENTS),
+        _ => unreachable!(),
+    }
+}
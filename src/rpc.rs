@@ -19,69 +19,154 @@
 
 //! The RPC protocol used by Kademlia.
 
+use bincode;
+use ed25519_dalek::SIGNATURE_LENGTH;
+use identity::{self, Keypair, PublicKey, Signature};
 use k_bucket::KBucketEntry;
 use node_id::NodeId;
-use storage;
+use puzzle;
+use record::Record;
+use storage::{self, Key, Value};
+use token::Token;
 
 /// 100MB should be enough for now.
 pub const RPC_MESSAGE_MAX_SIZE: usize = 100 * 1024 * 1024;
 
+/// Gets the bytes a signed message takes besides its request or response,
+/// that is, its sender, puzzle solution, public key, signature and kind.
+pub fn envelope_size() -> usize {
+    let message: RPCMessage = RPCMessage {
+        sender: NodeId::from_bytes([0; 20]),
+        kind: MessageKind::Request(RequestKind::Ping),
+        puzzle_solution: NodeId::from_bytes([0; 20]),
+        public_key: [0; 32],
+        signature: vec![0; SIGNATURE_LENGTH],
+    };
+    let ping: RequestKind = RequestKind::Ping;
+    (bincode::serialized_size(&message) - bincode::serialized_size(&ping)) as usize
+}
+
 /// A single RPC message.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RPCMessage {
+pub struct RPCMessage<K = NodeId, V = Vec<u8>> {
     /// The sender of the message.
     pub sender: NodeId,
     /// The message that was sent.
-    pub kind: MessageKind,
+    pub kind: MessageKind<K, V>,
+    /// The solution of the sender to the dynamic crypto puzzle.
+    pub puzzle_solution: puzzle::Solution,
+    /// The public key of the sender, from which `sender` must be derived.
+    pub public_key: PublicKey,
+    /// The signature of the sender, the message kind and the puzzle solution.
+    pub signature: Signature,
 }
 
-impl RPCMessage {
-    /// Trivially constructs a `RPCMessage`.
-    pub fn new(sender: NodeId, kind: MessageKind) -> Self {
-        RPCMessage { sender, kind }
+impl<K, V> RPCMessage<K, V>
+    where K: Key,
+          V: Value,
+{
+    /// Constructs an unsigned `RPCMessage`.
+    ///
+    /// The message needs to be signed with `sign` before sending it, which
+    /// `Node::send_message` takes care of, along with filling in the puzzle
+    /// solution.
+    pub fn new(sender: NodeId, kind: MessageKind<K, V>) -> Self {
+        RPCMessage {
+            sender,
+            kind,
+            puzzle_solution: NodeId::from_bytes([0; 20]),
+            public_key: [0; 32],
+            signature: vec![],
+        }
+    }
+
+    /// The bytes covered by the signature of this message.
+    fn signed_data(&self) -> Vec<u8> {
+        let data = (&self.sender, &self.kind, &self.puzzle_solution);
+        bincode::serialize(&data, bincode::Infinite)
+            .expect("Serializing to a Vec shouldn't fail")
+    }
+
+    /// Signs this message with the given keypair.
+    pub fn sign(&mut self, keypair: &Keypair) {
+        self.public_key = keypair.public_key();
+        self.signature = keypair.sign(&self.signed_data());
+    }
+
+    /// Returns whether the sender id matches the public key of the message,
+    /// and the signature is valid.
+    pub fn verify(&self) -> bool {
+        if identity::node_id_for(&self.public_key) != self.sender {
+            return false;
+        }
+
+        identity::verify(&self.public_key, &self.signed_data(), &self.signature)
     }
 }
 
 /// The different messages defined by the RPC protocol.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum MessageKind {
+pub enum MessageKind<K = NodeId, V = Vec<u8>> {
     /// A request message.
-    Request(RequestKind),
+    Request(RequestKind<K, V>),
     /// A response message.
-    Response(ResponseKind),
+    Response(ResponseKind<K, V>),
 }
 
 /// The different request kinds defined by the RPC protocol.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum RequestKind {
+pub enum RequestKind<K = NodeId, V = Vec<u8>> {
     /// A `PING` message.
     Ping,
-    /// A `FIND_NODE` message.
-    FindNode(NodeId),
-    /// A `STORE_NODE` message.
-    Store(storage::Key, storage::Value),
-    /// A `FIND_VALUE` message.
-    FindValue(storage::Key),
+    /// A `FIND_NODE` message, with the write token the receiver last gave us,
+    /// if any, which proves that we own our address.
+    FindNode(NodeId, Option<Token>),
+    /// A `STORE_NODE` message, with the sequence number the existing mutable
+    /// record is expected to have, if any, the proof of work of the sender,
+    /// and the write token the receiver last gave us, if any.
+    Store(K, Record<V>, Option<u64>, puzzle::Stamp, Option<Token>),
+    /// A `FIND_VALUE` message, with the write token the receiver last gave
+    /// us, if any, which proves that we own our address.
+    FindValue(K, Option<Token>),
+    /// A request to cache a record found in a lookup, with the proof of work
+    /// of the sender and the write token the receiver last gave us, if any,
+    /// which the receiver keeps for a time that decreases with its distance
+    /// to the key. There's no response to it.
+    Cache(K, Record<V>, puzzle::Stamp, Option<Token>),
+    /// A notice that the sender is leaving the network, and should be removed
+    /// from the routing table. There's no response to it.
+    Leave,
 }
 
 /// The different response kinds defined by the RPC protocol.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum ResponseKind {
+pub enum ResponseKind<K = NodeId, V = Vec<u8>> {
     /// A `PONG` message, as a response to a ping.
     Pong,
-    /// A `FIND_NODE` response, with the node addresses close to the nodes.
-    FindNode(Vec<KBucketEntry>),
-    /// A `FIND_VALUE` reply, with either a value or a list of closer nodes.
-    FindValue(FindValueResponse),
+    /// A `FIND_NODE` response, with the node addresses close to the nodes,
+    /// and a write token for the requester.
+    FindNode(Vec<KBucketEntry>, Token),
+    /// A `FIND_VALUE` reply, with either a value or a list of closer nodes,
+    /// and a write token for the requester.
+    FindValue(FindValueResponse<K, V>, Token),
+    /// A `STORE_NODE` reply, with the result of the store.
+    Store(K, Result<(), storage::StoreError>),
 }
 
 /// A response for a `FIND_VALUE`
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum FindValueResponse {
+pub enum FindValueResponse<K = NodeId, V = Vec<u8>> {
     /// A value was found for this key.
-    Value(storage::Key, storage::Value),
+    Value(K, Record<V>),
+
+    /// The value was not found on this node for this key, but here are some
+    /// nodes that are closer, and whether the farthest of them were left out
+    /// to respect `node::AMPLIFICATION_FACTOR`, in which case the rest can be
+    /// asked for again with the write token of the response.
+    CloserNodes(K, Vec<KBucketEntry>, bool),
 
-    /// The value was not found on this node, but here are some nodes that are
-    /// closer.
-    CloserNodes(Vec<KBucketEntry>),
+    /// The value is too big to send to an address that wasn't verified, see
+    /// `node::AMPLIFICATION_FACTOR`. The request needs to be sent again with
+    /// the write token of the response.
+    VerifyAddress(K),
 }
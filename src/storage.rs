@@ -19,46 +19,857 @@
 
 //! A definition of common storage-related types.
 
+use bincode;
+use identity::PublicKey;
 use node_id::NodeId;
-use std::collections::HashMap;
-use std::hash::{self, Hasher};
-use std::mem;
+use persistence::{Log, LogEntry, StoredRecord};
+use record::Record;
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The minimum number of entries in the log of a persistent store before it's
+/// compacted.
+pub const COMPACTION_MIN_ENTRIES: usize = 1024;
 
 /// A key in the distributed store.
-pub type Key = NodeId;
+///
+/// Keys need to be mapped to the id space, so that we know which nodes are
+/// responsible for them. Records whose key is derived from their contents, like
+/// content-addressed or signed records, are only valid under keys whose id is
+/// the derived one, so those are only available with `NodeId` keys.
+pub trait Key: Clone + Debug + Eq + Hash + Serialize + Deserialize + Send + 'static {
+    /// Maps this key to the id space.
+    fn to_id(&self) -> NodeId;
+
+    /// Gets the kind of the records stored under this key, if keys of this
+    /// type have one, which nodes use to apply per-kind policies, see
+    /// `validator::KindPolicy`.
+    fn kind(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl Key for NodeId {
+    fn to_id(&self) -> NodeId {
+        self.clone()
+    }
+}
+
+impl Key for String {
+    fn to_id(&self) -> NodeId {
+        hash(self.as_bytes())
+    }
+}
+
+/// A key within the namespace of an application, for records of a given kind.
+///
+/// The id of the key covers the namespace, the kind and the name, so different
+/// applications never collide, and the kind can't be changed without changing
+/// the id.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NamespacedKey {
+    /// The namespace of the application owning the key.
+    pub namespace: String,
+    /// The kind of the records stored under the key.
+    pub kind: String,
+    /// The name of the key within the namespace.
+    pub name: Vec<u8>,
+}
+
+impl NamespacedKey {
+    /// Creates a new key.
+    pub fn new<N, K>(namespace: N, kind: K, name: &[u8]) -> Self
+        where N: Into<String>,
+              K: Into<String>,
+    {
+        NamespacedKey {
+            namespace: namespace.into(),
+            kind: kind.into(),
+            name: name.to_vec(),
+        }
+    }
+}
+
+impl Key for NamespacedKey {
+    fn to_id(&self) -> NodeId {
+        // Every part is length-prefixed, so they can't bleed into each other.
+        let data = (&self.namespace, &self.kind, &self.name);
+        hash(&bincode::serialize(&data, bincode::Infinite)
+            .expect("Serializing to a Vec shouldn't fail"))
+    }
+
+    fn kind(&self) -> Option<&str> {
+        Some(&self.kind)
+    }
+}
 
 /// A value in the store.
 ///
-/// FIXME(emilio): Right now we only store blobs, but this doesn't need to be
-/// true forever.
+/// Values are serialized with the rest of the messages, so applications can
+/// store typed values directly.
+pub trait Value: Clone + Debug + PartialEq + Serialize + Deserialize + Send + 'static {
+    /// Gets the canonical bytes of this value, which are what gets signed in
+    /// signed records, and hashed in content-addressed ones.
+    ///
+    /// By default this is the serialized value.
+    fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self, bincode::Infinite)
+            .expect("Serializing to a Vec shouldn't fail")
+    }
+
+    /// Merges a concurrent version of this value into it, if this is a
+    /// mergeable value, see the `crdt` module.
+    ///
+    /// Returns false, leaving the value untouched, if values of this type are
+    /// replaced instead, which is the default.
+    fn merge_from(&mut self, _other: &Self) -> bool {
+        false
+    }
+}
+
+/// Blobs are stored as-is, so their hash is the hash of the raw bytes.
+impl Value for Vec<u8> {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.clone()
+    }
+}
+
+impl Value for String {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+/// Who a stored record is accounted to, for the purpose of quotas.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Source {
+    /// The record was stored by this node itself. These are not subject to the
+    /// per-source quota.
+    Local,
+    /// The record was stored by a node at this address.
+    Address(IpAddr),
+    /// The records signed by the owner of this key, which are accounted to
+    /// their publisher as well as to the address they come from, so that
+    /// neither a fresh keypair nor a fresh address gets around the quota.
+    Publisher(PublicKey),
+}
+
+impl Source {
+    /// Computes the source of a record, which was sent from `address`, or
+    /// stored locally if `address` is `None`.
+    pub fn of(address: Option<&SocketAddr>) -> Self {
+        match address {
+            Some(address) => Source::Address(address.ip()),
+            None => Source::Local,
+        }
+    }
+}
+
+/// Gets all the sources a record stored by `source` is accounted to.
+fn accounts<V: Value>(source: &Source, record: &Record<V>) -> Vec<Source> {
+    let mut accounts = vec![source.clone()];
+    if *source == Source::Local {
+        return accounts;
+    }
+
+    let publisher = match *record {
+        Record::Mutable(ref r) => Some(Source::Publisher(r.public_key)),
+        Record::Tombstone(ref t) => Some(Source::Publisher(t.public_key)),
+        _ => None,
+    };
+    if let Some(publisher) = publisher {
+        if publisher != *source {
+            accounts.push(publisher);
+        }
+    }
+    accounts
+}
+
+/// The limits of a store. The default values are fairly generous, and
+/// `usize::MAX` can be used to disable any of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum size of a single record, in bytes.
+    pub max_record_size: usize,
+    /// The maximum size of all the records together, in bytes.
+    pub max_total_bytes: usize,
+    /// The maximum number of keys.
+    pub max_keys: usize,
+    /// The maximum size of the records of a single `Source`, in bytes.
+    pub max_bytes_per_source: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_record_size: 1024 * 1024,
+            max_total_bytes: 256 * 1024 * 1024,
+            max_keys: 1024 * 1024,
+            max_bytes_per_source: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// The policy that decides which record to evict when the store is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evict the least recently stored or read record.
+    LeastRecentlyUsed,
+    /// Evict the record whose key is the farthest from our own id, since we
+    /// are the least responsible for it.
+    FarthestFirst,
+    /// Evict the record that expires the soonest. Records that never expire
+    /// are evicted last.
+    SoonestToExpire,
+}
+
+/// A record in the store, along with its metadata.
+#[derive(Debug, Clone)]
+pub struct Entry<V> {
+    /// The record itself.
+    pub record: Record<V>,
+    /// Who this record is accounted to.
+    pub source: Source,
+    /// The size of the record, in bytes.
+    pub size: usize,
+    /// When this record was stored.
+    pub stored_at: Instant,
+    /// When this record was last stored or read.
+    pub last_accessed: Instant,
+    /// When this record expires, if ever.
+    pub expires_at: Option<Instant>,
+}
+
+/// The actual store we use in each node. We keep everything in a standard
+/// `HashMap`, bounded by a set of `Limits`, and optionally in a log on disk, so
+/// that the records survive restarts.
+pub struct Store<K = NodeId, V = Vec<u8>> {
+    /// The id of the node owning this store.
+    own_id: NodeId,
+    /// The actual entries.
+    entries: HashMap<K, Entry<V>>,
+    /// The limits we enforce.
+    limits: Limits,
+    /// What to evict when we reach the limits.
+    policy: EvictionPolicy,
+    /// The size of all our records, in bytes.
+    total_bytes: usize,
+    /// The size of the records of each source, in bytes.
+    bytes_per_source: HashMap<Source, usize>,
+    /// The keys of the records that expire, by expiry time.
+    expiries: BTreeMap<Instant, Vec<K>>,
+    /// The log every change is written to, if this store is persistent.
+    log: Option<Log>,
+}
+
+impl<K, V> Store<K, V>
+    where K: Key,
+          V: Value,
+{
+    /// Creates an empty store for the node with id `own_id`, with the default
+    /// limits and least-recently-used eviction.
+    pub fn new(own_id: NodeId) -> Self {
+        Store {
+            own_id: own_id,
+            entries: HashMap::new(),
+            limits: Limits::default(),
+            policy: EvictionPolicy::LeastRecentlyUsed,
+            total_bytes: 0,
+            bytes_per_source: HashMap::new(),
+            expiries: BTreeMap::new(),
+            log: None,
+        }
+    }
+
+    /// Opens a persistent store for the node with id `own_id`, backed by the
+    /// log at `path`, and loads the records in it, if any.
+    ///
+    /// The records are loaded regardless of the limits, which only apply to
+    /// the records stored afterwards.
+    pub fn open<P>(own_id: NodeId, path: P) -> io::Result<Self>
+        where P: AsRef<Path>,
+    {
+        let (log, changes) = Log::open(path)?;
+        let mut store = Self::new(own_id);
+        for change in changes {
+            match change {
+                LogEntry::Put(key, stored) => {
+                    store.take(&key);
+                    let entry = Entry {
+                        size: bincode::serialized_size(&stored.record) as usize,
+                        record: stored.record,
+                        source: stored.source,
+                        stored_at: from_unix_ms(stored.stored_at),
+                        last_accessed: from_unix_ms(stored.stored_at),
+                        expires_at: stored.expires_at.map(from_unix_ms),
+                    };
+                    store.insert_entry(key, entry);
+                }
+                LogEntry::Delete(key) | LogEntry::Expire(key) => {
+                    store.take(&key);
+                }
+            }
+        }
+
+        store.remove_expired();
+        store.log = Some(log);
+        store.compact()?;
+        Ok(store)
+    }
+
+    /// Rewrites the log of a persistent store with just the records that are
+    /// alive. This is done automatically as the log grows.
+    pub fn compact(&mut self) -> io::Result<()> {
+        let live = match self.log {
+            Some(..) => {
+                self.entries.iter()
+                    .map(|(key, entry)| LogEntry::Put(key.clone(), stored(entry)))
+                    .collect::<Vec<_>>()
+            }
+            None => return Ok(()),
+        };
+
+        debug!("Compacting log with {} live records", live.len());
+        self.log.as_mut().unwrap().rewrite(&live)
+    }
+
+    /// Flushes the log of a persistent store to disk, so that it survives the
+    /// machine crashing.
+    pub fn sync(&self) -> io::Result<()> {
+        match self.log {
+            Some(ref log) => log.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes a change to the log, if this store is persistent.
+    fn persist(&mut self, change: LogEntry<K, V>) -> io::Result<()> {
+        match self.log {
+            Some(ref mut log) => log.append(&change),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes a removal to the log, if this store is persistent, and compacts
+    /// it if it's grown too much.
+    ///
+    /// There's nobody to report failures to, but the worst that can happen is
+    /// the record coming back after a restart.
+    fn persist_removal(&mut self, change: LogEntry<K, V>) {
+        if let Err(err) = self.persist(change) {
+            error!("Failed to write to the store log: {:?}", err);
+        }
+        self.compact_if_needed();
+    }
+
+    /// Compacts the log, if this store is persistent and the log has grown too
+    /// much.
+    fn compact_if_needed(&mut self) {
+        let needs_compaction = match self.log {
+            Some(ref log) => {
+                log.len() > cmp::max(COMPACTION_MIN_ENTRIES, 2 * self.entries.len())
+            }
+            None => false,
+        };
+
+        if needs_compaction {
+            if let Err(err) = self.compact() {
+                error!("Failed to compact the store log: {:?}", err);
+            }
+        }
+    }
+
+    /// Sets the limits of this store.
+    ///
+    /// The records stored already are not evicted until a new one is stored.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Gets the limits of this store.
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Sets the eviction policy of this store.
+    pub fn set_eviction_policy(&mut self, policy: EvictionPolicy) {
+        self.policy = policy;
+    }
+
+    /// Gets the eviction policy of this store.
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        self.policy
+    }
+
+    /// Gets the number of records in the store.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether the store is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Gets the size of all the records in the store, in bytes.
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    /// Gets the size of the records accounted to a given source, in bytes.
+    pub fn bytes_for(&self, source: &Source) -> usize {
+        self.bytes_per_source.get(source).cloned().unwrap_or(0)
+    }
+
+    /// Iterates over all the entries in the store, including expired ones.
+    pub fn iter<'a>(&'a self) -> hash_map::Iter<'a, K, Entry<V>> {
+        self.entries.iter()
+    }
+
+    /// Gets the entry for a key, if any, without considering it accessed.
+    pub fn entry(&self, key: &K) -> Option<&Entry<V>> {
+        self.entries.get(key)
+    }
+
+    /// Gets the record for a key, if any and not expired, without considering
+    /// it accessed.
+    pub fn peek(&self, key: &K) -> Option<&Record<V>> {
+        let entry = self.entries.get(key)?;
+        if is_expired(entry, Instant::now()) {
+            return None;
+        }
+        Some(&entry.record)
+    }
+
+    /// Gets the record for a key, if any and not expired.
+    pub fn get(&mut self, key: &K) -> Option<&Record<V>> {
+        let now = Instant::now();
+        let entry = self.entries.get_mut(key)?;
+        if is_expired(entry, now) {
+            return None;
+        }
+        entry.last_accessed = now;
+        Some(&entry.record)
+    }
+
+    /// Removes the record for a key, returning its entry.
+    pub fn remove(&mut self, key: &K) -> Option<Entry<V>> {
+        let entry = self.take(key)?;
+        self.persist_removal(LogEntry::Delete(key.clone()));
+        Some(entry)
+    }
+
+    /// Removes the record for a key, without writing it to the log.
+    fn take(&mut self, key: &K) -> Option<Entry<V>> {
+        let entry = self.entries.remove(key)?;
+        self.total_bytes -= entry.size;
+        for account in accounts(&entry.source, &entry.record) {
+            let now_empty = {
+                let bytes = self.bytes_per_source.get_mut(&account).unwrap();
+                *bytes -= entry.size;
+                *bytes == 0
+            };
+            if now_empty {
+                self.bytes_per_source.remove(&account);
+            }
+        }
+        if let Some(expires_at) = entry.expires_at {
+            let now_empty = {
+                let keys = self.expiries.get_mut(&expires_at).unwrap();
+                keys.retain(|k| k != key);
+                keys.is_empty()
+            };
+            if now_empty {
+                self.expiries.remove(&expires_at);
+            }
+        }
+        Some(entry)
+    }
+
+    /// Removes all the expired records.
+    ///
+    /// Records are kept ordered by expiry time, so this only looks at the
+    /// records that actually expired.
+    pub fn remove_expired(&mut self) {
+        let now = Instant::now();
+        loop {
+            let expired = match self.expiries.iter().next() {
+                Some((&expires_at, keys)) if expires_at <= now => keys.clone(),
+                _ => break,
+            };
+            for key in expired {
+                self.take(&key);
+                self.persist_removal(LogEntry::Expire(key));
+            }
+        }
+    }
+
+    /// Inserts a record, evicting other records according to the eviction
+    /// policy if needed.
+    ///
+    /// If the store is persistent and the record can't be written to the log,
+    /// the store is left untouched and `StoreError::Io` is returned.
+    ///
+    /// This doesn't check whether the record can replace the existing one, see
+    /// `Record::check_update` for that.
+    pub fn insert(&mut self,
+                  key: K,
+                  record: Record<V>,
+                  source: Source,
+                  expires_at: Option<Instant>)
+                  -> Result<(), StoreError> {
+        let size = bincode::serialized_size(&record) as usize;
+        if size > self.limits.max_record_size ||
+           size > self.limits.max_total_bytes {
+            return Err(StoreError::TooLarge(self.limits.max_record_size));
+        }
+
+        let now = Instant::now();
+        if source != Source::Local {
+            let replaced = self.entries.get(&key)
+                .map(|e| (accounts(&e.source, &e.record), e.size));
+            for account in accounts(&source, &record) {
+                let replaced_size = match replaced {
+                    Some((ref accounts, size)) if accounts.contains(&account) => size,
+                    _ => 0,
+                };
+                if self.bytes_for(&account) - replaced_size + size >
+                       self.limits.max_bytes_per_source {
+                    return Err(StoreError::QuotaExceeded);
+                }
+            }
+        }
+
+        let new_entry = Entry {
+            record: record,
+            source: source,
+            size: size,
+            stored_at: now,
+            last_accessed: now,
+            expires_at: expires_at,
+        };
+
+        // Clear the way for the new entry, preferring to get rid of expired
+        // stuff, and figure out what we'd need to evict.
+        let old_entry = self.take(&key);
+        self.remove_expired();
+
+        let mut victims = vec![];
+        let mut freed_bytes = 0;
+        while self.total_bytes - freed_bytes + size > self.limits.max_total_bytes ||
+              self.entries.len() - victims.len() >= self.limits.max_keys {
+            // Note that we can only run out of candidates if `max_keys` is
+            // zero.
+            let victim = self.eviction_candidate(&victims).and_then(|victim| {
+                if self.policy != EvictionPolicy::LeastRecentlyUsed &&
+                   self.evicts_before(&key, &new_entry, &victim) {
+                    return None;
+                }
+                Some(victim)
+            });
+            let victim = match victim {
+                Some(victim) => victim,
+                None => {
+                    if let Some(old_entry) = old_entry {
+                        self.insert_entry(key, old_entry);
+                    }
+                    return Err(StoreError::StoreFull);
+                }
+            };
+            freed_bytes += self.entries[&victim].size;
+            victims.push(victim);
+        }
+
+        if self.log.is_some() {
+            if let Err(err) = self.persist(LogEntry::Put(key.clone(), stored(&new_entry))) {
+                error!("Failed to write to the store log: {:?}", err);
+                if let Some(old_entry) = old_entry {
+                    self.insert_entry(key, old_entry);
+                }
+                return Err(StoreError::Io(err.to_string()));
+            }
+        }
+
+        // Compacting the log before the new entry is in would lose it.
+        for victim in victims {
+            debug!("Evicting {:?}", victim);
+            self.take(&victim);
+            if let Err(err) = self.persist(LogEntry::Delete(victim)) {
+                error!("Failed to write to the store log: {:?}", err);
+            }
+        }
+        self.insert_entry(key, new_entry);
+        self.compact_if_needed();
+        Ok(())
+    }
+
+    /// Inserts an entry, without checking any limit.
+    fn insert_entry(&mut self, key: K, entry: Entry<V>) {
+        self.total_bytes += entry.size;
+        for account in accounts(&entry.source, &entry.record) {
+            *self.bytes_per_source.entry(account).or_insert(0) += entry.size;
+        }
+        if let Some(expires_at) = entry.expires_at {
+            self.expiries.entry(expires_at).or_insert_with(Vec::new).push(key.clone());
+        }
+        self.entries.insert(key, entry);
+    }
+
+    /// Returns whether the (not yet inserted) `entry` at `key` would be evicted
+    /// before the existing record at `victim`.
+    fn evicts_before(&self, key: &K, entry: &Entry<V>, victim: &K) -> bool {
+        let victim_entry = &self.entries[victim];
+        match self.policy {
+            EvictionPolicy::LeastRecentlyUsed => {
+                entry.last_accessed < victim_entry.last_accessed
+            }
+            EvictionPolicy::FarthestFirst => {
+                self.own_id.xor(&key.to_id()) > self.own_id.xor(&victim.to_id())
+            }
+            EvictionPolicy::SoonestToExpire => {
+                match (entry.expires_at, victim_entry.expires_at) {
+                    (Some(a), Some(b)) => a < b,
+                    (Some(..), None) => true,
+                    (None, _) => false,
+                }
+            }
+        }
+    }
+
+    /// Picks the record to evict next according to our eviction policy,
+    /// ignoring the ones in `excluded`.
+    fn eviction_candidate(&self, excluded: &[K]) -> Option<K> {
+        let mut candidate: Option<&K> = None;
+        for (key, entry) in &self.entries {
+            if excluded.contains(key) {
+                continue;
+            }
+            let better = match candidate {
+                None => true,
+                Some(c) => self.evicts_before(key, entry, c),
+            };
+            if better {
+                candidate = Some(key);
+            }
+        }
+        candidate.cloned()
+    }
+}
+
+/// Gets the on-disk representation of an entry.
+fn stored<V: Value>(entry: &Entry<V>) -> StoredRecord<V> {
+    StoredRecord {
+        record: entry.record.clone(),
+        source: entry.source.clone(),
+        stored_at: to_unix_ms(entry.stored_at),
+        expires_at: entry.expires_at.map(to_unix_ms),
+    }
+}
+
+/// Converts an instant to milliseconds since the Unix epoch.
+fn to_unix_ms(instant: Instant) -> u64 {
+    let now = Instant::now();
+    let unix_now = SystemTime::now().duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+    let unix = if instant > now {
+        unix_now + (instant - now)
+    } else {
+        unix_now.checked_sub(now - instant).unwrap_or(Duration::from_secs(0))
+    };
+    unix.as_secs() * 1000 + unix.subsec_nanos() as u64 / 1_000_000
+}
+
+/// Converts milliseconds since the Unix epoch to an instant.
 ///
-/// Actually, perhaps the code should be more generic across keys and values...
-/// Oh well.
-pub type Value = Vec<u8>;
+/// Times before the start of the monotonic clock are clamped to now.
+fn from_unix_ms(ms: u64) -> Instant {
+    let now = Instant::now();
+    let unix_now = SystemTime::now().duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+    let unix = Duration::from_millis(ms);
+    if unix > unix_now {
+        now + (unix - unix_now)
+    } else {
+        now.checked_sub(unix_now - unix).unwrap_or(now)
+    }
+}
+
+/// Returns whether an entry is expired at a given time.
+fn is_expired<V>(entry: &Entry<V>, now: Instant) -> bool {
+    entry.expires_at.map_or(false, |t| t <= now)
+}
 
-/// The actual store we use in each node. Right now we use a standard `HashMap`.
+/// The reasons a node can refuse to store a record.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StoreError {
+    /// The signature of a mutable record is not valid, or the record doesn't
+    /// belong to the key.
+    InvalidSignature,
+    /// The record has a sequence number older than the one we have, which is
+    /// attached.
+    StaleSequence(u64),
+    /// The compare-and-swap sequence number didn't match the one we have,
+    /// which is attached.
+    CasMismatch(u64),
+    /// A record tried to overwrite a record of a kind it can't replace, like a
+    /// plain value overwriting a signed or content-addressed record.
+    Protected,
+    /// The key of a content-addressed record is not the hash of its value.
+    HashMismatch,
+    /// The record is bigger than the maximum record size, which is attached.
+    TooLarge(usize),
+    /// The source of the record used all of its quota.
+    QuotaExceeded,
+    /// The store is full, and the record would be the first one to be evicted.
+    StoreFull,
+    /// The validator of the node rejected the record, for the attached
+    /// reason.
+    Rejected(String),
+    /// The validator of the node preferred the record it already had.
+    NotSelected,
+    /// The record under the key was deleted, and the tombstone hasn't expired
+    /// yet.
+    Deleted,
+    /// The store request didn't carry a stamp with the attached number of
+    /// leading zero bits, see `puzzle::StampDifficulty`.
+    InsufficientWork(u32),
+    /// The store request didn't carry a valid write token issued to the
+    /// sender recently, see the `token` module.
+    InvalidToken,
+    /// The sender made too many store requests lately, see the `rate_limit`
+    /// module.
+    Throttled,
+    /// The record couldn't be written to disk, for the attached reason.
+    Io(String),
+    /// Storing the versions would leave more than `version::MAX_SIBLINGS`
+    /// siblings, or one of them has more than `version::MAX_WRITERS` writers in
+    /// its clock.
+    TooManyVersions,
+}
+
+
+/// Map unequivocally a given blob to a key.
 ///
-/// We could use some persistent storage or what not.
-pub type Store = HashMap<Key, Value>;
+/// This is the SHA-256 digest of the blob, truncated to the 160 bits of the
+/// key space, so it's suitable to content-address values.
+pub fn hash(val: &[u8]) -> NodeId {
+    NodeId::digest(val)
+}
 
+/// Map unequivocally a given `Value` to a key, hashing its canonical bytes.
+pub fn hash_value<V: Value>(val: &V) -> NodeId {
+    hash(&val.to_bytes())
+}
 
-/// Map unequivocally a given `Value` to a `Key`.
-#[allow(deprecated)] // SipHasher is deprecated, oh well.
-pub fn hash(val: &[u8]) -> Key {
-    let mut hasher = hash::SipHasher::new();
-    hasher.write(val);
+#[test]
+fn store_limits_and_eviction() {
+    use std::net::Ipv4Addr;
 
-    // These are 64 bit, we could use up to 160, but for now we just use these
-    // 64 bits.
-    //
-    // That means that the distribution in our hashmap isn't going to be great,
-    // but oh well.
-    let hash: u64 = hasher.finish();
-    let mut bytes = [0; 20];
+    let own_id = NodeId::from_bytes([0; 20]);
+    let mut near = [0; 20];
+    near[19] = 1;
+    let near = NodeId::from_bytes(near);
+    let mut far = [0; 20];
+    far[0] = 0xff;
+    let far = NodeId::from_bytes(far);
+    let mut farther = [0xff; 20];
+    farther[19] = 0xfe;
+    let farther = NodeId::from_bytes(farther);
 
-    for i in 0..mem::size_of::<u64>() {
-        bytes[i] = ((hash & (0xff << i)) >> i) as u8;
-    }
+    let record: Record = Record::Plain(vec![0; 10]);
+    let size = bincode::serialized_size(&record) as usize;
+    let peer = Source::Address(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+
+    let mut store: Store = Store::new(own_id);
+    store.set_eviction_policy(EvictionPolicy::FarthestFirst);
+    store.set_limits(Limits {
+        max_record_size: size,
+        max_total_bytes: 2 * size,
+        max_keys: 10,
+        max_bytes_per_source: size,
+    });
+
+    assert_eq!(store.insert(near.clone(), Record::Plain(vec![0; 11]), Source::Local, None),
+               Err(StoreError::TooLarge(size)));
+    assert!(store.insert(near.clone(), record.clone(), peer.clone(), None).is_ok());
+    assert_eq!(store.insert(far.clone(), record.clone(), peer.clone(), None),
+               Err(StoreError::QuotaExceeded));
+    assert!(store.insert(far.clone(), record.clone(), Source::Local, None).is_ok());
+
+    // The store is full, so the farthest key needs to go away, but not in
+    // favour of an even farther one.
+    assert_eq!(store.insert(farther, record.clone(), Source::Local, None),
+               Err(StoreError::StoreFull));
+    let mut nearer = [0; 20];
+    nearer[19] = 2;
+    let nearer = NodeId::from_bytes(nearer);
+    assert!(store.insert(nearer.clone(), record.clone(), Source::Local, None).is_ok());
+    assert!(store.peek(&far).is_none());
+    assert!(store.peek(&near).is_some());
+    assert!(store.peek(&nearer).is_some());
+    assert_eq!(store.total_bytes(), 2 * size);
+    assert_eq!(store.bytes_for(&peer), size);
+}
+
+#[test]
+fn signed_records_are_accounted_to_sender_and_publisher() {
+    use identity::Keypair;
+    use rand;
+    use record::MutableRecord;
+    use std::net::Ipv4Addr;
+
+    let mut rng = rand::OsRng::new().unwrap();
+    let publisher = Keypair::generate(&mut rng);
+    let one = Source::Address(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+    let two = Source::Address(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+    let record = |keypair: &Keypair, salt: &[u8]| {
+        let record = MutableRecord::new(keypair, salt.to_vec(), 0, vec![0; 10]);
+        (record.key(), Record::Mutable(record))
+    };
+    let (key, first) = record(&publisher, b"first");
+    let size = bincode::serialized_size(&first) as usize;
+
+    let mut store: Store = Store::new(NodeId::from_bytes([0; 20]));
+    store.set_limits(Limits { max_bytes_per_source: size, ..Limits::default() });
+    assert!(store.insert(key, first, one.clone(), None).is_ok());
+    assert_eq!(store.bytes_for(&one), size);
+    assert_eq!(store.bytes_for(&Source::Publisher(publisher.public_key())), size);
+
+    // Neither a fresh keypair nor a fresh address gets around the quota.
+    let (key, second) = record(&Keypair::generate(&mut rng), b"second");
+    assert_eq!(store.insert(key, second, one.clone(), None),
+               Err(StoreError::QuotaExceeded));
+    let (key, third) = record(&publisher, b"third");
+    assert_eq!(store.insert(key.clone(), third.clone(), two.clone(), None),
+               Err(StoreError::QuotaExceeded));
+
+    // Expired records go away as soon as anything else is stored.
+    let (expiring, fourth) = record(&publisher, b"fourth");
+    let now = Instant::now();
+    assert!(store.insert(expiring.clone(), fourth, Source::Local, Some(now)).is_ok());
+    assert!(store.insert(key, third, Source::Local, None).is_ok());
+    assert!(store.entry(&expiring).is_none());
+    assert_eq!(store.len(), 2);
+}
+
+#[test]
+fn namespaced_keys_dont_collide() {
+    let users = NamespacedKey::new("chat", "profile", b"alice");
+    let other_app = NamespacedKey::new("forum", "profile", b"alice");
+    let other_kind = NamespacedKey::new("chat", "avatar", b"alice");
+    let ambiguous = NamespacedKey::new("cha", "tprofile", b"alice");
 
-    Key::from_bytes(bytes)
+    assert_ne!(users.to_id(), other_app.to_id());
+    assert_ne!(users.to_id(), other_kind.to_id());
+    assert_ne!(users.to_id(), ambiguous.to_id());
+    assert_ne!(users.to_id(), "alice".to_owned().to_id());
+    assert_eq!(users.kind(), Some("profile"));
 }
@@ -0,0 +1,132 @@
+/*
+ * Kademlia.rs - A WIP Kademlia algorithm implementation in Rust.
+ *
+ * Copyright (C) 2017 Emilio Cobos Álvarez <emilio@crisal.io>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Write tokens, as in BitTorrent's [BEP 5][bep5].
+//!
+//! Nodes hand out a token to every node that looks up nodes or values, and
+//! only accept store requests that carry a recent token issued to the same
+//! address. This proves that the sender can receive traffic at the address it
+//! claims, so stores can't be sent with forged source addresses.
+//!
+//! Tokens are an HMAC of the requester's IP address under a secret that's
+//! rotated every few minutes. Tokens issued with the previous secret are
+//! still accepted, so a token lives between one and two rotation periods.
+//!
+//! [bep5]: http://bittorrent.org/beps/bep_0005.html
+
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// A write token, opaque to everyone but the node that issued it.
+pub type Token = Vec<u8>;
+
+/// How often the secret tokens are derived from is rotated, in seconds.
+pub const SECRET_ROTATION_SECS: u64 = 5 * 60;
+
+/// The size of the tokens we issue, in bytes.
+const TOKEN_SIZE: usize = 16;
+
+/// Issues and verifies the write tokens of a node.
+pub struct TokenIssuer {
+    /// The secret new tokens are derived from.
+    current: [u8; 32],
+    /// The secret before the last rotation, still accepted.
+    previous: [u8; 32],
+    /// When the secret was last rotated.
+    rotated_at: Instant,
+}
+
+impl TokenIssuer {
+    /// Creates a token issuer with a fresh random secret.
+    pub fn new<R: Rng>(rng: &mut R) -> Self {
+        let mut current = [0; 32];
+        rng.fill_bytes(&mut current);
+        TokenIssuer {
+            current,
+            previous: current,
+            rotated_at: Instant::now(),
+        }
+    }
+
+    /// Rotates the secret if it's older than `SECRET_ROTATION_SECS`,
+    /// invalidating the tokens issued before the previous rotation.
+    pub fn rotate_if_needed<R: Rng>(&mut self, rng: &mut R) {
+        if self.rotated_at.elapsed() < Duration::from_secs(SECRET_ROTATION_SECS) {
+            return;
+        }
+        self.rotate(rng);
+    }
+
+    /// Replaces the secret with a fresh one, keeping the current one as the
+    /// previous secret.
+    fn rotate<R: Rng>(&mut self, rng: &mut R) {
+        self.previous = self.current;
+        rng.fill_bytes(&mut self.current);
+        self.rotated_at = Instant::now();
+    }
+
+    /// Issues a token for a node at `ip`.
+    pub fn issue(&self, ip: &IpAddr) -> Token {
+        token_for(&self.current, ip)
+    }
+
+    /// Returns whether `token` was issued to a node at `ip` with the current
+    /// or the previous secret.
+    pub fn verify(&self, ip: &IpAddr, token: &[u8]) -> bool {
+        // Not constant time, but the tokens are bound to the requester's
+        // address, which would need to be spoofed to learn anything useful.
+        token == &token_for(&self.current, ip)[..] ||
+            token == &token_for(&self.previous, ip)[..]
+    }
+}
+
+/// Derives the token for `ip` under `secret`.
+fn token_for(secret: &[u8; 32], ip: &IpAddr) -> Token {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret)
+        .expect("HMAC accepts keys of any size");
+    match *ip {
+        IpAddr::V4(ip) => mac.update(&ip.octets()),
+        IpAddr::V6(ip) => mac.update(&ip.octets()),
+    }
+    mac.finalize().into_bytes()[..TOKEN_SIZE].to_vec()
+}
+
+#[test]
+fn tokens_are_bound_to_address_and_secret() {
+    use rand;
+
+    let mut rng = rand::OsRng::new().unwrap();
+    let mut issuer = TokenIssuer::new(&mut rng);
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+    let token = issuer.issue(&ip);
+    assert!(issuer.verify(&ip, &token));
+    assert!(!issuer.verify(&other, &token));
+    assert!(!issuer.verify(&ip, &[]));
+
+    // Tokens survive one rotation, but not two.
+    issuer.rotate(&mut rng);
+    assert!(issuer.verify(&ip, &token));
+    issuer.rotate(&mut rng);
+    assert!(!issuer.verify(&ip, &token));
+}
@@ -0,0 +1,75 @@
+/*
+ * Kademlia.rs - A WIP Kademlia algorithm implementation in Rust.
+ *
+ * Copyright (C) 2017 Emilio Cobos Álvarez <emilio@crisal.io>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Application-defined rules about what can be stored in the network.
+
+use node_id::NodeId;
+use record::Record;
+use std::time::Duration;
+
+/// An interface that lets applications decide which records a node accepts,
+/// like the `Validator` of libp2p.
+///
+/// The validator is consulted after the built-in checks (signatures, sequence
+/// numbers, content hashes...) pass, both for the records other nodes ask us
+/// to store, and for the records we find in lookups.
+pub trait RecordValidator<K = NodeId, V = Vec<u8>> : Send {
+    /// Validates a record that is going to be stored under `key`, returning
+    /// the reason to reject it otherwise, which is reported to the sender.
+    ///
+    /// This is where schemas, sizes or signatures can be enforced.
+    fn validate(&self, key: &K, record: &Record<V>) -> Result<(), String>;
+
+    /// Picks the best of a non-empty list of valid records for the same key,
+    /// returning its index.
+    ///
+    /// When storing, `records` is the existing record followed by the new one,
+    /// and the store is refused if the existing one is selected.
+    ///
+    /// By default the last record is picked, that is, new records replace old
+    /// ones.
+    fn select(&self, _key: &K, records: &[Record<V>]) -> usize {
+        records.len() - 1
+    }
+}
+
+/// The rules for the records of a given kind, see `Key::kind`.
+///
+/// This lets many applications share a network while enforcing different
+/// rules for their records.
+pub struct KindPolicy<K = NodeId, V = Vec<u8>> {
+    /// How long records of this kind are kept, if they expire.
+    pub ttl: Option<Duration>,
+    /// The maximum size of a record of this kind, in bytes, if it's smaller
+    /// than the limit of the store.
+    pub max_record_size: Option<usize>,
+    /// The validator for records of this kind, which is consulted along with
+    /// the validator of the node, if any.
+    pub validator: Option<Box<dyn RecordValidator<K, V>>>,
+}
+
+impl<K, V> Default for KindPolicy<K, V> {
+    fn default() -> Self {
+        KindPolicy {
+            ttl: None,
+            max_record_size: None,
+            validator: None,
+        }
+    }
+}
@@ -0,0 +1,169 @@
+/*
+ * Kademlia.rs - A WIP Kademlia algorithm implementation in Rust.
+ *
+ * Copyright (C) 2017 Emilio Cobos Álvarez <emilio@crisal.io>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Versioned values, whose versions are tracked with vector clocks, like in
+//! Dynamo.
+//!
+//! Each write carries a vector clock with a counter per writer. A version
+//! whose clock is dominated by another one is outdated and gets discarded,
+//! but concurrent versions are kept side by side as siblings, until a writer
+//! that has seen all of them reconciles them into a new version.
+
+use node_id::NodeId;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use storage::Value;
+
+/// The maximum number of siblings a node keeps for a key. Stores that would
+/// leave more are refused, and the writers need to reconcile them first.
+pub const MAX_SIBLINGS: usize = 16;
+
+/// The maximum number of writers in a vector clock. Clocks with more are
+/// refused, so that they can't grow without bound.
+pub const MAX_WRITERS: usize = 64;
+
+/// A vector clock, with a counter for each writer.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VectorClock {
+    counters: BTreeMap<NodeId, u64>,
+}
+
+impl VectorClock {
+    /// Creates an empty clock.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets the counter of a writer.
+    pub fn get(&self, writer: &NodeId) -> u64 {
+        self.counters.get(writer).cloned().unwrap_or(0)
+    }
+
+    /// Increments the counter of a writer, as it writes a new version.
+    pub fn increment(&mut self, writer: &NodeId) {
+        let counter = self.counters.entry(writer.clone()).or_insert(0);
+        *counter = counter.saturating_add(1);
+    }
+
+    /// Gets the number of writers with a counter in this clock.
+    pub fn len(&self) -> usize {
+        self.counters.len()
+    }
+
+    /// Returns whether no writer has a counter in this clock.
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+
+    /// Merges another clock into this one, keeping the biggest counter of
+    /// each writer.
+    pub fn merge(&mut self, other: &Self) {
+        for (writer, counter) in &other.counters {
+            let existing = self.counters.entry(writer.clone()).or_insert(0);
+            if *existing < *counter {
+                *existing = *counter;
+            }
+        }
+    }
+
+    /// Compares two clocks, returning `None` if they're concurrent, that is,
+    /// if neither of them happened before the other.
+    pub fn compare(&self, other: &Self) -> Option<Ordering> {
+        let mut ordering = Ordering::Equal;
+        for writer in self.counters.keys().chain(other.counters.keys()) {
+            match (ordering, self.get(writer).cmp(&other.get(writer))) {
+                (_, Ordering::Equal) => {}
+                (Ordering::Equal, o) => ordering = o,
+                (a, b) if a != b => return None,
+                _ => {}
+            }
+        }
+        Some(ordering)
+    }
+}
+
+/// A value along with the clock of its version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Versioned<V = Vec<u8>> {
+    /// The clock of this version.
+    pub clock: VectorClock,
+    /// The value.
+    pub value: V,
+}
+
+/// Merges `new` versions into `siblings`, discarding the versions dominated by
+/// any other one, and keeping the concurrent ones.
+pub fn merge_siblings<V, I>(siblings: &mut Vec<Versioned<V>>, new: I)
+    where V: Value,
+          I: IntoIterator<Item = Versioned<V>>,
+{
+    for version in new {
+        let outdated = siblings.iter().any(|s| {
+            match version.clock.compare(&s.clock) {
+                Some(Ordering::Less) | Some(Ordering::Equal) => true,
+                _ => false,
+            }
+        });
+        if outdated {
+            continue;
+        }
+
+        siblings.retain(|s| s.clock.compare(&version.clock) != Some(Ordering::Less));
+        siblings.push(version);
+    }
+}
+
+/// Gets the clock that a version reconciling all of `siblings` has to descend
+/// from.
+pub fn context<V>(siblings: &[Versioned<V>]) -> VectorClock {
+    let mut clock = VectorClock::new();
+    for sibling in siblings {
+        clock.merge(&sibling.clock);
+    }
+    clock
+}
+
+#[test]
+fn concurrent_versions_are_siblings() {
+    let a = NodeId::from_bytes([1; 20]);
+    let b = NodeId::from_bytes([2; 20]);
+
+    let mut first = VectorClock::new();
+    first.increment(&a);
+    let mut from_a = first.clone();
+    from_a.increment(&a);
+    let mut from_b = first.clone();
+    from_b.increment(&b);
+
+    assert_eq!(first.compare(&from_a), Some(Ordering::Less));
+    assert_eq!(from_a.compare(&from_b), None);
+
+    let mut siblings = vec![Versioned { clock: first, value: vec![1] }];
+    merge_siblings(&mut siblings, vec![
+        Versioned { clock: from_a.clone(), value: vec![2] },
+        Versioned { clock: from_b.clone(), value: vec![3] },
+    ]);
+    assert_eq!(siblings.len(), 2);
+
+    let mut reconciled = context(&siblings);
+    reconciled.increment(&a);
+    merge_siblings(&mut siblings, vec![Versioned { clock: reconciled, value: vec![4] }]);
+    assert_eq!(siblings.len(), 1);
+    assert_eq!(siblings[0].value, vec![4]);
+}